@@ -0,0 +1,23 @@
+#!/bin/sh
+# Regenerates data/cities.tsv from the GeoNames cities15000 extract (every place with a
+# population of at least 15000) and the admin1 codes that name its regions.
+# GeoNames data is licensed under CC BY 4.0, see data/README.md.
+set -eu
+
+cd "$(dirname "$0")"
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+curl -fsSL -o "$tmp/cities15000.zip" https://download.geonames.org/export/dump/cities15000.zip
+curl -fsSL -o "$tmp/admin1CodesASCII.txt" https://download.geonames.org/export/dump/admin1CodesASCII.txt
+unzip -q -d "$tmp" "$tmp/cities15000.zip"
+
+{
+    printf '# Generated by data/fetch-cities.sh from GeoNames (https://www.geonames.org/), CC BY 4.0.\n'
+    printf '# name\tregion\tcountry\tlatitude\tlongitude\n'
+    # cities15000.txt columns: 2 name, 5 latitude, 6 longitude, 9 country code, 11 admin1 code.
+    awk -F '\t' '
+        FNR == NR { regions[$1] = $2; next }
+        { print $2 "\t" regions[$9 "." $11] "\t" $9 "\t" $5 "\t" $6 }
+    ' "$tmp/admin1CodesASCII.txt" "$tmp/cities15000.txt" | sort -t "$(printf '\t')" -k3,3 -k1,1
+} > cities.tsv
@@ -0,0 +1,89 @@
+use std::fmt;
+
+use lambda_http::{tracing, Body, Error, Response};
+
+use crate::auth::AuthError;
+
+/// Error returned by every handler, rendered by `function_handler` as
+/// `{"code": ..., "message": ..., "request_id": ...}` with a matching status.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: u16,
+    /// Stable snake_case identifier for clients to match on.
+    pub code: &'static str,
+    pub message: String,
+    pub headers: Vec<(&'static str, String)>,
+    /// Underlying failure of a 5xx error; logged, never sent to the client.
+    pub source: Option<Error>,
+}
+
+impl ApiError {
+    pub fn new(status: u16, code: &'static str, message: impl Into<String>) -> Self {
+        Self { status, code, message: message.into(), headers: vec![], source: None }
+    }
+
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(400, code, message)
+    }
+
+    pub fn forbidden(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(403, code, message)
+    }
+
+    pub fn not_found(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(404, code, message)
+    }
+
+    pub fn internal(source: Error) -> Self {
+        Self { source: Some(source), ..Self::new(500, "internal", "internal error") }
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn into_response(self, request_id: &str) -> Response<Body> {
+        if let Some(source) = &self.source {
+            tracing::error!(status = self.status, code = self.code, error = ?source, "request failed");
+        }
+        let body = serde_json::json!({
+            "code": self.code,
+            "message": self.message,
+            "request_id": request_id,
+        });
+        let mut response = Response::builder()
+            .status(self.status)
+            .header("content-type", "application/json");
+        for (name, value) in self.headers {
+            response = response.header(name, value);
+        }
+        response.body(body.to_string().into()).unwrap()
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {}", self.status, self.code, self.message)
+    }
+}
+
+/// Any other failure (SDK calls, serialization, ...) is an internal error.
+impl<E: Into<Error>> From<E> for ApiError {
+    fn from(e: E) -> Self {
+        Self::internal(e.into())
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(e: AuthError) -> Self {
+        let status = e.status();
+        let code = e.reason();
+        let message = e.to_string();
+        let source = match e {
+            AuthError::KeysUnavailable(source) | AuthError::Internal(source) => Some(source),
+            _ => None,
+        };
+        Self { source, ..Self::new(status, code, message) }
+    }
+}
@@ -0,0 +1,633 @@
+use std::{cmp::Ordering, fmt};
+
+use lambda_http::{http::{header::AUTHORIZATION, HeaderMap}, tracing, Error, Request};
+use openssl::{asn1::Asn1Time, base64, error::ErrorStack, hash::MessageDigest, nid::Nid, pkey::{Id, PKey, Public}, sign::Verifier, stack::Stack, x509::{store::X509StoreBuilder, verify::X509VerifyFlags, X509StoreContext, X509}};
+
+use crate::{cert_revocation::{cert_id, is_revoked}, info_upload::DynamoDBClient, nonce_store::{parse_nonce, timestamp_in_window, NonceStore}, request_signing::{signed_payload, SignatureVersion}, server_key::{server_public_keys, trusted_roots}, session::{verify_token, Scope}};
+
+/// Most intermediates accepted in `X-Auth-Chain`.
+const MAX_CHAIN_LEN: usize = 4;
+
+/// Who made a request. `function_handler` stores it in the request extensions before calling a route handler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Caller {
+    Anonymous,
+    User(Identity),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub username: String,
+    /// [`cert_id`] of the device certificate the request (or its session) was authenticated with.
+    pub cert_id: String,
+}
+
+impl Caller {
+    pub fn of(event: &Request) -> Self {
+        event.extensions().get::<Self>().cloned().unwrap_or(Self::Anonymous)
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        match self {
+            Self::Anonymous => None,
+            Self::User(identity) => Some(&identity.username),
+        }
+    }
+}
+
+/// Everything that can go wrong while authenticating a request.
+#[derive(Debug)]
+pub enum AuthError {
+    /// A required `X-*` header is absent.
+    MissingHeader(&'static str),
+    /// A header is present but not visible ASCII.
+    InvalidHeader(&'static str),
+    /// `X-Auth-Cert` is not base64 DER of an X.509 certificate.
+    MalformedCertificate,
+    /// `X-Auth-Signature` is not base64.
+    MalformedSignature,
+    /// The certificate's public key can not be used to verify signatures.
+    UnsupportedKey,
+    /// The certificate is outside its validity period, not issued by a server key or trusted root, or not issued to the user.
+    UntrustedCertificate,
+    CertificateRevoked,
+    /// `X-Nonce` is malformed or its timestamp is outside the accepted window.
+    InvalidNonce,
+    ReplayedNonce,
+    UnsupportedSignatureVersion,
+    BadSignature,
+    /// The bearer token is malformed or its MAC does not match.
+    InvalidToken,
+    ExpiredToken,
+    /// The bearer token is valid but was not issued for this kind of request.
+    InsufficientScope,
+    /// The route only accepts certificate-signed requests, not session tokens.
+    CertificateRequired,
+    /// `SESSION_TOKEN_SECRET` is not set or empty.
+    SessionsUnavailable,
+    /// The server public keys could not be loaded.
+    KeysUnavailable(Error),
+    /// A backing service failed while checking the request.
+    Internal(Error),
+}
+
+impl AuthError {
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::MissingHeader(_) | Self::InvalidHeader(_) | Self::MalformedCertificate | Self::MalformedSignature => 400,
+            Self::UnsupportedKey | Self::UntrustedCertificate | Self::CertificateRevoked | Self::InvalidNonce
+            | Self::ReplayedNonce | Self::UnsupportedSignatureVersion | Self::BadSignature
+            | Self::InvalidToken | Self::ExpiredToken | Self::CertificateRequired => 401,
+            Self::InsufficientScope => 403,
+            Self::KeysUnavailable(_) | Self::SessionsUnavailable => 503,
+            Self::Internal(_) => 500,
+        }
+    }
+
+    /// Stable identifier for clients to match on.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::MissingHeader(_) => "missing_header",
+            Self::InvalidHeader(_) => "invalid_header",
+            Self::MalformedCertificate => "malformed_certificate",
+            Self::MalformedSignature => "malformed_signature",
+            Self::UnsupportedKey => "unsupported_key",
+            Self::UntrustedCertificate => "untrusted_certificate",
+            Self::CertificateRevoked => "certificate_revoked",
+            Self::InvalidNonce => "invalid_nonce",
+            Self::ReplayedNonce => "replayed_nonce",
+            Self::UnsupportedSignatureVersion => "unsupported_signature_version",
+            Self::BadSignature => "bad_signature",
+            Self::InvalidToken => "invalid_token",
+            Self::ExpiredToken => "expired_token",
+            Self::InsufficientScope => "insufficient_scope",
+            Self::CertificateRequired => "certificate_required",
+            Self::SessionsUnavailable => "sessions_unavailable",
+            Self::KeysUnavailable(_) => "keys_unavailable",
+            Self::Internal(_) => "internal",
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingHeader(header) => write!(f, "missing {header} header"),
+            Self::InvalidHeader(header) => write!(f, "invalid {header} header"),
+            Self::MalformedCertificate => write!(f, "certificate is not valid base64 DER"),
+            Self::MalformedSignature => write!(f, "signature is not valid base64"),
+            Self::UnsupportedKey => write!(f, "certificate key type is not supported"),
+            Self::UntrustedCertificate => write!(f, "certificate is not trusted for this user"),
+            Self::CertificateRevoked => write!(f, "certificate has been revoked"),
+            Self::InvalidNonce => write!(f, "nonce is malformed or expired"),
+            Self::ReplayedNonce => write!(f, "nonce has already been used"),
+            Self::UnsupportedSignatureVersion => write!(f, "signature version is not supported"),
+            Self::BadSignature => write!(f, "signature does not match the request"),
+            Self::InvalidToken => write!(f, "session token is not valid"),
+            Self::ExpiredToken => write!(f, "session token has expired"),
+            Self::InsufficientScope => write!(f, "session token does not allow this request"),
+            Self::CertificateRequired => write!(f, "this request must be signed with a device certificate"),
+            Self::SessionsUnavailable => write!(f, "sessions are not available"),
+            Self::KeysUnavailable(_) => write!(f, "server keys are temporarily unavailable"),
+            Self::Internal(_) => write!(f, "internal error"),
+        }
+    }
+}
+
+impl From<ErrorStack> for AuthError {
+    fn from(e: ErrorStack) -> Self {
+        Self::Internal(e.into())
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &'static str) -> Result<&'a str, AuthError> {
+    headers.get(name)
+        .ok_or(AuthError::MissingHeader(name))?
+        .to_str()
+        .map_err(|_| AuthError::InvalidHeader(name))
+}
+
+/// Whether a request carries any credentials, as opposed to being anonymous.
+pub fn has_credentials(event: &Request) -> bool {
+    event.headers().contains_key("X-Username") || event.headers().contains_key(AUTHORIZATION)
+}
+
+/// Authenticates a request with an `Authorization: Bearer` session token if it has one
+/// and `allow_session` is set, with the certificate and signature check otherwise.
+pub async fn authenticate(event: &Request, allow_session: bool) -> Result<Identity, AuthError> {
+    let Some(authorization) = event.headers().get(AUTHORIZATION) else {
+        return authenticate_certificate(event).await;
+    };
+    if !allow_session {
+        return Err(AuthError::CertificateRequired);
+    }
+    let token = authorization.to_str().ok()
+        .and_then(|it| it.strip_prefix("Bearer "))
+        .ok_or(AuthError::InvalidHeader("Authorization"))?;
+    let claims = verify_token(token, Scope::for_method(event.method())).await?;
+    Ok(Identity { username: claims.sub, cert_id: claims.cert_id })
+}
+
+/// Runs the certificate and signature checks for a request.
+async fn authenticate_certificate(event: &Request) -> Result<Identity, AuthError> {
+    let username = header(event.headers(), "X-Username")?;
+    let cert_str = header(event.headers(), "X-Auth-Cert")?;
+    let sig_str = header(event.headers(), "X-Auth-Signature")?;
+    let nonce = header(event.headers(), "X-Nonce")?;
+    let cert = base64::decode_block(cert_str).ok()
+        .and_then(|der| X509::from_der(&der).ok())
+        .ok_or(AuthError::MalformedCertificate)?;
+    let chain = match event.headers().get("X-Auth-Chain") {
+        Some(chain) => parse_chain(chain.to_str().map_err(|_| AuthError::InvalidHeader("X-Auth-Chain"))?)?,
+        None => vec![],
+    };
+    let signature = base64::decode_block(sig_str).map_err(|_| AuthError::MalformedSignature)?;
+    let cert_pub_key = cert.public_key().map_err(|_| AuthError::UnsupportedKey)?;
+    if !matches!(cert_pub_key.id(), Id::RSA | Id::EC | Id::ED25519) {
+        return Err(AuthError::UnsupportedKey);
+    }
+    let server_keys = server_public_keys().await.map_err(AuthError::KeysUnavailable)?;
+    tracing::debug!("auth headers parsed");
+
+    if !verify_cert(&cert, &chain, username, &server_keys)? {
+        return Err(AuthError::UntrustedCertificate);
+    }
+    tracing::debug!("certificate valid for user");
+    let client = DynamoDBClient::new().await.map_err(AuthError::Internal)?;
+    let cert_id = cert_id(&cert)?;
+    if is_revoked(&client, &cert_id).await.map_err(AuthError::Internal)? {
+        return Err(AuthError::CertificateRevoked);
+    }
+    let signature_version = SignatureVersion::from_request(event).ok_or(AuthError::UnsupportedSignatureVersion)?;
+    let timestamp = parse_nonce(nonce, signature_version == SignatureVersion::V1)
+        .filter(|it| timestamp_in_window(*it))
+        .ok_or(AuthError::InvalidNonce)?;
+    tracing::debug!("nonce timestamp valid");
+    let payload = signed_payload(signature_version, event, username, nonce)?;
+    if !verify_signature(&cert_pub_key, &payload, &signature)? {
+        return Err(AuthError::BadSignature);
+    }
+    tracing::debug!(?signature_version, "signature valid");
+    let nonce_store = NonceStore::from_env().await.map_err(AuthError::Internal)?;
+    if !nonce_store.record(username, nonce, timestamp).await.map_err(AuthError::Internal)? {
+        return Err(AuthError::ReplayedNonce);
+    }
+    Ok(Identity { username: username.to_string(), cert_id })
+}
+
+/// Verifies a device signature with the scheme matching the certificate key:
+/// RSA (PKCS#1 v1.5) and ECDSA (DER encoded, e.g. P-256 from a secure enclave) sign a SHA-256 digest,
+/// Ed25519 signs the payload itself.
+fn verify_signature(key: &PKey<Public>, payload: &[u8], signature: &[u8]) -> Result<bool, AuthError> {
+    match key.id() {
+        Id::RSA | Id::EC => {
+            let mut verifier = Verifier::new(MessageDigest::sha256(), key)?;
+            verifier.update(payload)?;
+            Ok(verifier.verify(signature).unwrap_or(false))
+        }
+        Id::ED25519 => {
+            let mut verifier = Verifier::new_without_digest(key)?;
+            Ok(verifier.verify_oneshot(signature, payload).unwrap_or(false))
+        }
+        _ => Err(AuthError::UnsupportedKey),
+    }
+}
+
+/// Parses `X-Auth-Chain`: comma separated base64 DER intermediate certificates, leaf side first.
+pub fn parse_chain(header: &str) -> Result<Vec<X509>, AuthError> {
+    let chain = header.split(',')
+        .map(str::trim)
+        .filter(|it| !it.is_empty())
+        .map(|it| base64::decode_block(it).ok().and_then(|der| X509::from_der(&der).ok()).ok_or(AuthError::MalformedCertificate))
+        .collect::<Result<Vec<_>, _>>()?;
+    if chain.len() > MAX_CHAIN_LEN {
+        return Err(AuthError::MalformedCertificate);
+    }
+    Ok(chain)
+}
+
+/// Checks that `cert` is currently valid and was issued for `username`, see [`cert_issued_to`].
+fn verify_cert(cert: &X509, chain: &[X509], username: &str, server_keys: &[PKey<Public>]) -> Result<bool, ErrorStack> {
+    let now = Asn1Time::days_from_now(0)?;
+    if cert.not_before().compare(&now)? == Ordering::Greater || cert.not_after().compare(&now)? == Ordering::Less {
+        return Ok(false);
+    }
+    if !server_keys.iter().any(|key| cert.verify(key).unwrap_or(false)) && !chains_to_root(cert, chain, trusted_roots(), true)? {
+        return Ok(false);
+    }
+    cert_belongs_to(cert, username)
+}
+
+/// Checks that `cert` was issued for `username`, ignoring validity periods.
+/// The certificate must be signed directly by one of the active server keys, or chain up
+/// to one of the [`trusted_roots`] through the intermediates in `chain`.
+pub fn cert_issued_to(cert: &X509, chain: &[X509], username: &str, server_keys: &[PKey<Public>]) -> Result<bool, ErrorStack> {
+    if !server_keys.iter().any(|key| cert.verify(key).unwrap_or(false)) && !chains_to_root(cert, chain, trusted_roots(), false)? {
+        return Ok(false);
+    }
+    cert_belongs_to(cert, username)
+}
+
+/// Runs openssl path validation from `cert` through `chain` to one of `roots`.
+fn chains_to_root(cert: &X509, chain: &[X509], roots: &[X509], check_time: bool) -> Result<bool, ErrorStack> {
+    if roots.is_empty() {
+        return Ok(false);
+    }
+    let mut store = X509StoreBuilder::new()?;
+    for root in roots {
+        store.add_cert(root.clone())?;
+    }
+    if !check_time {
+        store.set_flags(X509VerifyFlags::NO_CHECK_TIME)?;
+    }
+    let store = store.build();
+    let mut intermediates = Stack::new()?;
+    for intermediate in chain {
+        intermediates.push(intermediate.clone())?;
+    }
+    let mut context = X509StoreContext::new()?;
+    context.init(&store, cert, &intermediates, |context| context.verify_cert())
+}
+
+fn cert_belongs_to(cert: &X509, username: &str) -> Result<bool, ErrorStack> {
+    if let Some(field) = cert.subject_name().entries_by_nid(Nid::ACCOUNT).next() {
+        if field.data().as_utf8()?.to_string() == username {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use lambda_http::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn header_requires_visible_ascii() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Username", HeaderValue::from_static("alice"));
+        headers.insert("X-Nonce", HeaderValue::from_bytes("nonce-é".as_bytes()).unwrap());
+        assert_eq!(header(&headers, "X-Username").unwrap(), "alice");
+        assert!(matches!(header(&headers, "X-Nonce"), Err(AuthError::InvalidHeader("X-Nonce"))));
+        assert!(matches!(header(&headers, "X-Auth-Cert"), Err(AuthError::MissingHeader("X-Auth-Cert"))));
+    }
+
+    #[test]
+    fn parse_chain_rejects_malformed_certificates() {
+        assert!(parse_chain("").unwrap().is_empty());
+        assert!(parse_chain(" , ").unwrap().is_empty());
+        assert!(matches!(parse_chain("not base64!"), Err(AuthError::MalformedCertificate)));
+        assert!(matches!(parse_chain("zé"), Err(AuthError::MalformedCertificate)));
+        // Valid base64, but not DER.
+        assert!(matches!(parse_chain(&base64::encode_block(b"not a certificate")), Err(AuthError::MalformedCertificate)));
+    }
+
+    #[test]
+    fn parse_chain_limits_length() {
+        let cert = base64::encode_block(&test_cert().to_der().unwrap());
+        assert_eq!(parse_chain(&[cert.as_str(); MAX_CHAIN_LEN].join(",")).unwrap().len(), MAX_CHAIN_LEN);
+        assert!(matches!(parse_chain(&[cert.as_str(); MAX_CHAIN_LEN + 1].join(",")), Err(AuthError::MalformedCertificate)));
+    }
+
+    fn test_cert() -> X509 {
+        let key = PKey::generate_ed25519().unwrap();
+        let mut name = openssl::x509::X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "test").unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.sign(&key, MessageDigest::null()).unwrap();
+        builder.build()
+    }
+
+    fn sign(key: &PKey<openssl::pkey::Private>, payload: &[u8]) -> Vec<u8> {
+        let mut signer = match key.id() {
+            Id::ED25519 => openssl::sign::Signer::new_without_digest(key).unwrap(),
+            _ => openssl::sign::Signer::new(MessageDigest::sha256(), key).unwrap(),
+        };
+        signer.sign_oneshot_to_vec(payload).unwrap()
+    }
+
+    #[test]
+    fn verify_signature_per_key_type() {
+        let p256 = openssl::ec::EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let keys = [
+            PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap(),
+            PKey::from_ec_key(openssl::ec::EcKey::generate(&p256).unwrap()).unwrap(),
+            PKey::generate_ed25519().unwrap(),
+        ];
+        let payload = b"POST\n/post-info\nalice\n1700000000000.abcdefghijklmnop";
+        for key in keys {
+            let public = PKey::public_key_from_der(&key.public_key_to_der().unwrap()).unwrap();
+            let signature = sign(&key, payload);
+            assert!(verify_signature(&public, payload, &signature).unwrap(), "{:?}", key.id());
+
+            let mut tampered_payload = payload.to_vec();
+            tampered_payload[0] ^= 1;
+            assert!(!verify_signature(&public, &tampered_payload, &signature).unwrap(), "{:?}", key.id());
+
+            let mut tampered_signature = signature.clone();
+            *tampered_signature.last_mut().unwrap() ^= 1;
+            assert!(!verify_signature(&public, payload, &tampered_signature).unwrap(), "{:?}", key.id());
+            assert!(!verify_signature(&public, payload, b"").unwrap(), "{:?}", key.id());
+        }
+    }
+
+    #[test]
+    fn verify_signature_rejects_other_key_types() {
+        let key = PKey::generate_x25519().unwrap();
+        let public = PKey::public_key_from_der(&key.public_key_to_der().unwrap()).unwrap();
+        assert!(matches!(verify_signature(&public, b"payload", b"signature"), Err(AuthError::UnsupportedKey)));
+    }
+
+    type PrivateKey = PKey<openssl::pkey::Private>;
+
+    fn generate_key() -> PrivateKey {
+        let p256 = openssl::ec::EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(openssl::ec::EcKey::generate(&p256).unwrap()).unwrap()
+    }
+
+    fn days_ago(days: i64) -> Asn1Time {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64;
+        Asn1Time::from_unix(now - days * 24 * 60 * 60).unwrap()
+    }
+
+    /// Issues a certificate for `key`, self-signed when there is no `issuer`. `ca` is the
+    /// basicConstraints CA flag, or `None` to leave the extension out.
+    fn issue(cn: &str, account: Option<&str>, key: &PrivateKey, issuer: Option<(&X509, &PrivateKey)>, ca: Option<bool>, validity: (Asn1Time, Asn1Time)) -> X509 {
+        let mut name = openssl::x509::X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", cn).unwrap();
+        if let Some(account) = account {
+            name.append_entry_by_nid(Nid::ACCOUNT, account).unwrap();
+        }
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = openssl::bn::BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(issuer.map_or(&name, |(cert, _)| cert.subject_name())).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder.set_not_before(&validity.0).unwrap();
+        builder.set_not_after(&validity.1).unwrap();
+        match ca {
+            Some(true) => builder.append_extension(openssl::x509::extension::BasicConstraints::new().critical().ca().build().unwrap()).unwrap(),
+            Some(false) => builder.append_extension(openssl::x509::extension::BasicConstraints::new().critical().build().unwrap()).unwrap(),
+            None => {}
+        }
+        builder.sign(issuer.map_or(key, |(_, key)| key), MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    fn valid() -> (Asn1Time, Asn1Time) {
+        (days_ago(1), Asn1Time::days_from_now(30).unwrap())
+    }
+
+    struct Chain {
+        root: X509,
+        intermediate: X509,
+        intermediate_key: PrivateKey,
+    }
+
+    /// A root and an intermediate CA to issue device certificates from.
+    fn ca_chain(intermediate_ca: Option<bool>) -> Chain {
+        let (root_key, intermediate_key) = (generate_key(), generate_key());
+        let root = issue("root", None, &root_key, None, Some(true), valid());
+        let intermediate = issue("intermediate", None, &intermediate_key, Some((&root, &root_key)), intermediate_ca, valid());
+        Chain { root, intermediate, intermediate_key }
+    }
+
+    fn leaf(chain: &Chain, validity: (Asn1Time, Asn1Time)) -> X509 {
+        issue("device", Some("alice"), &generate_key(), Some((&chain.intermediate, &chain.intermediate_key)), Some(false), validity)
+    }
+
+    #[test]
+    fn valid_chain() {
+        let chain = ca_chain(Some(true));
+        let cert = leaf(&chain, valid());
+        let roots = [chain.root.clone()];
+        assert!(chains_to_root(&cert, std::slice::from_ref(&chain.intermediate), &roots, true).unwrap());
+        assert!(cert_belongs_to(&cert, "alice").unwrap());
+        assert!(!cert_belongs_to(&cert, "bob").unwrap());
+        // Without trusted roots nothing chains.
+        assert!(!chains_to_root(&cert, std::slice::from_ref(&chain.intermediate), &[], true).unwrap());
+        // Nor to a root that did not issue the intermediate.
+        let other_root = ca_chain(Some(true)).root;
+        assert!(!chains_to_root(&cert, &[chain.intermediate], &[other_root], true).unwrap());
+    }
+
+    #[test]
+    fn missing_intermediate() {
+        let chain = ca_chain(Some(true));
+        let cert = leaf(&chain, valid());
+        assert!(!chains_to_root(&cert, &[], &[chain.root], true).unwrap());
+    }
+
+    #[test]
+    fn expired_leaf_only_passes_without_time_check() {
+        let chain = ca_chain(Some(true));
+        let cert = leaf(&chain, (days_ago(30), days_ago(1)));
+        let roots = [chain.root.clone()];
+        assert!(!chains_to_root(&cert, std::slice::from_ref(&chain.intermediate), &roots, true).unwrap());
+        assert!(chains_to_root(&cert, std::slice::from_ref(&chain.intermediate), &roots, false).unwrap());
+        // verify_cert checks the validity period even for certificates issued by a server key.
+        let server_key = generate_key();
+        let cert = issue("device", Some("alice"), &generate_key(), Some((&chain.root, &server_key)), Some(false), (days_ago(30), days_ago(1)));
+        let server_keys = [PKey::public_key_from_der(&server_key.public_key_to_der().unwrap()).unwrap()];
+        assert!(!verify_cert(&cert, &[], "alice", &server_keys).unwrap());
+        assert!(cert_issued_to(&cert, &[], "alice", &server_keys).unwrap());
+    }
+
+    #[test]
+    fn intermediate_must_be_a_ca() {
+        for intermediate_ca in [Some(false), None] {
+            let chain = ca_chain(intermediate_ca);
+            let cert = leaf(&chain, valid());
+            assert!(!chains_to_root(&cert, &[chain.intermediate], &[chain.root], true).unwrap(), "{intermediate_ca:?}");
+        }
+    }
+
+    #[test]
+    fn server_key_issued_cert() {
+        let server_key = generate_key();
+        let issuer = issue("server", None, &server_key, None, Some(true), valid());
+        let cert = issue("device", Some("alice"), &generate_key(), Some((&issuer, &server_key)), Some(false), valid());
+        let server_keys = [PKey::public_key_from_der(&server_key.public_key_to_der().unwrap()).unwrap()];
+        assert!(verify_cert(&cert, &[], "alice", &server_keys).unwrap());
+        assert!(!verify_cert(&cert, &[], "bob", &server_keys).unwrap());
+        assert!(!verify_cert(&cert, &[], "alice", &[]).unwrap());
+    }
+
+    #[test]
+    fn cert_id_tells_issuers_apart() {
+        // Every certificate `issue` makes has the same serial.
+        let ids = ["first", "second"].map(|cn| {
+            let issuer_key = generate_key();
+            let issuer = issue(cn, None, &issuer_key, None, Some(true), valid());
+            cert_id(&issue("device", Some("alice"), &generate_key(), Some((&issuer, &issuer_key)), Some(false), valid())).unwrap()
+        });
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(ids[0].split_once(':').unwrap().1, ids[1].split_once(':').unwrap().1);
+    }
+
+    fn request(headers: &[(&'static str, &[u8])]) -> Request {
+        let mut event = lambda_http::http::Request::builder().method("POST").uri("https://api.example.com/post-info");
+        for (name, value) in headers {
+            event = event.header(*name, HeaderValue::from_bytes(value).unwrap());
+        }
+        event.body(lambda_http::Body::Empty).unwrap()
+    }
+
+    /// A complete set of certificate headers for `cert`, with `overrides` replacing or adding some.
+    fn certificate_request(cert: &X509, overrides: &[(&'static str, &[u8])]) -> Request {
+        let cert = base64::encode_block(&cert.to_der().unwrap());
+        let signature = base64::encode_block(&[0; 64]);
+        let mut headers: Vec<(&'static str, &[u8])> = vec![
+            ("X-Username", b"alice"),
+            ("X-Auth-Cert", cert.as_bytes()),
+            ("X-Auth-Signature", signature.as_bytes()),
+            ("X-Nonce", b"1700000000000.abcdefghijklmnop"),
+        ];
+        for (name, value) in overrides {
+            headers.retain(|(it, _)| it != name);
+            headers.push((name, value));
+        }
+        request(&headers)
+    }
+
+    #[tokio::test]
+    async fn authenticate_rejects_missing_and_non_ascii_headers() {
+        assert!(matches!(authenticate(&request(&[]), true).await, Err(AuthError::MissingHeader("X-Username"))));
+        assert!(matches!(authenticate(&request(&[("X-Username", b"alice")]), true).await, Err(AuthError::MissingHeader("X-Auth-Cert"))));
+        let cert = test_cert();
+        let event = certificate_request(&cert, &[("X-Username", "alicé".as_bytes())]);
+        assert!(matches!(authenticate(&event, true).await, Err(AuthError::InvalidHeader("X-Username"))));
+        let mut event = certificate_request(&cert, &[]);
+        event.headers_mut().remove("X-Nonce");
+        assert!(matches!(authenticate(&event, true).await, Err(AuthError::MissingHeader("X-Nonce"))));
+    }
+
+    #[tokio::test]
+    async fn authenticate_rejects_garbage_certificates() {
+        let cert = test_cert();
+        let not_der = base64::encode_block(b"not a certificate");
+        for garbage in [&b"not base64!"[..], b"", not_der.as_bytes()] {
+            let event = certificate_request(&cert, &[("X-Auth-Cert", garbage)]);
+            assert!(matches!(authenticate(&event, true).await, Err(AuthError::MalformedCertificate)), "{garbage:?}");
+        }
+        let event = certificate_request(&cert, &[("X-Auth-Chain", not_der.as_bytes())]);
+        assert!(matches!(authenticate(&event, true).await, Err(AuthError::MalformedCertificate)));
+        let event = certificate_request(&cert, &[("X-Auth-Chain", "é".as_bytes())]);
+        assert!(matches!(authenticate(&event, true).await, Err(AuthError::InvalidHeader("X-Auth-Chain"))));
+    }
+
+    #[tokio::test]
+    async fn authenticate_rejects_garbage_signatures() {
+        let event = certificate_request(&test_cert(), &[("X-Auth-Signature", b"***")]);
+        assert!(matches!(authenticate(&event, true).await, Err(AuthError::MalformedSignature)));
+    }
+
+    #[tokio::test]
+    async fn authenticate_rejects_keys_that_can_not_sign() {
+        let issuer_key = PKey::generate_ed25519().unwrap();
+        let mut name = openssl::x509::X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::ACCOUNT, "alice").unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&PKey::generate_x25519().unwrap()).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.sign(&issuer_key, MessageDigest::null()).unwrap();
+        let event = certificate_request(&builder.build(), &[]);
+        assert!(matches!(authenticate(&event, true).await, Err(AuthError::UnsupportedKey)));
+    }
+
+    #[tokio::test]
+    async fn authenticate_rejects_garbage_authorization() {
+        for garbage in [&b"Basic YWxpY2U6c2VjcmV0"[..], b"Bearer", b"bearer token", "Bearer é".as_bytes()] {
+            let event = request(&[("Authorization", garbage)]);
+            assert!(matches!(authenticate(&event, true).await, Err(AuthError::InvalidHeader("Authorization"))), "{garbage:?}");
+        }
+        for garbage in [&b"Bearer "[..], b"Bearer not-a-token", b"Bearer v1.claims"] {
+            let event = request(&[("Authorization", garbage)]);
+            assert!(matches!(authenticate(&event, true).await, Err(AuthError::InvalidToken)), "{garbage:?}");
+        }
+        let event = request(&[("Authorization", b"Bearer v1.claims.mac")]);
+        assert!(matches!(authenticate(&event, false).await, Err(AuthError::CertificateRequired)));
+    }
+
+    #[test]
+    fn status_and_reason() {
+        let cases = [
+            (AuthError::MissingHeader("X-Nonce"), 400, "missing_header"),
+            (AuthError::InvalidHeader("X-Nonce"), 400, "invalid_header"),
+            (AuthError::MalformedCertificate, 400, "malformed_certificate"),
+            (AuthError::MalformedSignature, 400, "malformed_signature"),
+            (AuthError::UnsupportedKey, 401, "unsupported_key"),
+            (AuthError::UntrustedCertificate, 401, "untrusted_certificate"),
+            (AuthError::CertificateRevoked, 401, "certificate_revoked"),
+            (AuthError::InvalidNonce, 401, "invalid_nonce"),
+            (AuthError::ReplayedNonce, 401, "replayed_nonce"),
+            (AuthError::UnsupportedSignatureVersion, 401, "unsupported_signature_version"),
+            (AuthError::BadSignature, 401, "bad_signature"),
+            (AuthError::InvalidToken, 401, "invalid_token"),
+            (AuthError::ExpiredToken, 401, "expired_token"),
+            (AuthError::CertificateRequired, 401, "certificate_required"),
+            (AuthError::InsufficientScope, 403, "insufficient_scope"),
+            (AuthError::SessionsUnavailable, 503, "sessions_unavailable"),
+            (AuthError::KeysUnavailable("no keys".into()), 503, "keys_unavailable"),
+            (AuthError::Internal("boom".into()), 500, "internal"),
+        ];
+        for (error, status, reason) in cases {
+            assert_eq!((error.status(), error.reason()), (status, reason), "{error:?}");
+        }
+    }
+}
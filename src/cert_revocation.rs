@@ -0,0 +1,61 @@
+use std::{collections::HashMap, time::SystemTime};
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use lambda_http::{Body, Error, Request, Response};
+use openssl::{base64, error::ErrorStack, x509::X509};
+
+use crate::{api_error::ApiError, auth::{cert_issued_to, parse_chain, Caller}, info_upload::DynamoDBClient, server_key::server_public_keys};
+
+/// Keyed by [`cert_id`].
+const REVOKED_CERTS_TABLE: &str = "SocialMediaRevokedCerts";
+
+#[derive(serde::Deserialize)]
+struct RevokeRequest {
+    /// Base64 DER of the device certificate to revoke, same encoding as `X-Auth-Cert`.
+    cert: String,
+    /// Intermediates the certificate was issued through, same encoding as `X-Auth-Chain`.
+    chain: Option<String>,
+}
+
+/// Identifies a certificate in the revocation list and in session tokens:
+/// `<hex SHA-256 of the DER issuer name>:<hex serial>`, since serials are only unique per issuer.
+pub fn cert_id(cert: &X509) -> Result<String, ErrorStack> {
+    let issuer = openssl::sha::sha256(&cert.issuer_name().to_der()?);
+    let issuer = issuer.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+    Ok(format!("{issuer}:{}", cert.serial_number().to_bn()?.to_hex_str()?))
+}
+
+pub async fn is_revoked(client: &DynamoDBClient, cert_id: &str) -> Result<bool, Error> {
+    let key = [("cert_id".into(), AttributeValue::S(cert_id.to_string()))].into();
+    Ok(client.get_item(REVOKED_CERTS_TABLE, key).await?.is_some())
+}
+
+/// Revokes one of the caller's own device certificates, e.g. after a phone was lost.
+/// The certificate does not have to be the one the request was signed with.
+pub async fn revoke_cert(event: Request) -> Result<Response<Body>, ApiError> {
+    let username = Caller::of(&event).username().unwrap().to_string();
+    let Ok(request) = serde_json::from_slice::<RevokeRequest>(event.body()) else {
+        return Err(ApiError::bad_request("invalid_body", "body must be {\"cert\": ...}"));
+    };
+    let Some(cert) = base64::decode_block(&request.cert).ok().and_then(|der| X509::from_der(&der).ok()) else {
+        return Err(ApiError::bad_request("malformed_certificate", "cert is not valid base64 DER"));
+    };
+    let chain = parse_chain(request.chain.as_deref().unwrap_or_default())?;
+    let server_keys = server_public_keys().await?;
+    if let Ok(true) = cert_issued_to(&cert, &chain, &username, &server_keys) {} else {
+        return Err(ApiError::forbidden("not_your_certificate", "certificate was not issued to the caller"));
+    }
+
+    let now = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+    let client = DynamoDBClient::new().await?;
+    let mut item = HashMap::new();
+    item.insert("cert_id".into(), AttributeValue::S(cert_id(&cert)?));
+    item.insert("username".into(), AttributeValue::S(username));
+    item.insert("revoked_at".into(), AttributeValue::N(now.as_millis().to_string()));
+    client.put_item(REVOKED_CERTS_TABLE, item).await?;
+
+    Ok(Response::builder()
+        .status(200)
+        .body(Body::from(()))
+        .unwrap())
+}
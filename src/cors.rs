@@ -0,0 +1,51 @@
+use lambda_http::{http::{HeaderValue, Method}, Body, Request, Response};
+
+use crate::router::allow_header;
+
+/// Request headers browsers may send, listed in preflight responses.
+const ALLOWED_HEADERS: &str = "Content-Type, Authorization, X-Username, X-Auth-Cert, X-Auth-Chain, X-Auth-Signature, X-Auth-Version, X-Nonce, Idempotency-Key";
+/// Response headers scripts may read.
+const EXPOSED_HEADERS: &str = "Allow, Retry-After, X-Request-Id, Idempotent-Replayed";
+const MAX_AGE_SECS: u32 = 600;
+
+/// Returns the value for `Access-Control-Allow-Origin` if the request's `Origin` is allowed.
+///
+/// Allowed origins are a comma separated list in `CORS_ALLOWED_ORIGINS`, e.g.
+/// `https://example.com,https://staging.example.com`, or `*` for any origin.
+/// Without it no CORS headers are sent and browsers block cross-origin calls.
+pub fn allowed_origin(event: &Request) -> Option<String> {
+    let origin = event.headers().get("Origin")?.to_str().ok()?;
+    let allowed = std::env::var("CORS_ALLOWED_ORIGINS").ok()?;
+    let allowed = allowed.split(',').map(str::trim).collect::<Vec<_>>();
+    if allowed.contains(&"*") {
+        return Some("*".to_string());
+    }
+    allowed.contains(&origin).then(|| origin.to_string())
+}
+
+/// Whether the request is a CORS preflight, which browsers send without credentials.
+pub fn is_preflight(event: &Request) -> bool {
+    event.method() == Method::OPTIONS && event.headers().contains_key("Access-Control-Request-Method")
+}
+
+/// Answers a preflight for a path that accepts `methods`.
+pub fn preflight_response(methods: &[Method]) -> Response<Body> {
+    Response::builder()
+        .status(204)
+        .header("Access-Control-Allow-Methods", allow_header(methods))
+        .header("Access-Control-Allow-Headers", ALLOWED_HEADERS)
+        .header("Access-Control-Max-Age", MAX_AGE_SECS)
+        .body(Body::Empty)
+        .unwrap()
+}
+
+/// Adds the CORS headers every response needs, including errors, so browsers let scripts read them.
+pub fn add_cors_headers(response: &mut Response<Body>, origin: Option<&str>) {
+    let headers = response.headers_mut();
+    headers.append("Vary", HeaderValue::from_static("Origin"));
+    let Some(Ok(origin)) = origin.map(HeaderValue::from_str) else {
+        return;
+    };
+    headers.insert("Access-Control-Allow-Origin", origin);
+    headers.insert("Access-Control-Expose-Headers", HeaderValue::from_static(EXPOSED_HEADERS));
+}
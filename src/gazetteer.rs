@@ -0,0 +1,207 @@
+use std::sync::OnceLock;
+
+use crate::geo::{GeoPoint, EARTH_RADIUS_KM};
+
+/// `name, region, country, latitude, longitude` per line, tab separated. A curated subset of
+/// GeoNames cities (CC BY 4.0, see `data/README.md`); `data/fetch-cities.sh` writes the full
+/// `cities15000` extract in the same format. Lines starting with `#` are comments.
+const CITIES: &str = include_str!("../data/cities.tsv");
+/// Points farther than this from every known city get no place name.
+const MAX_DISTANCE_KM: f64 = 150.;
+
+/// The nearest known city to a post.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Place {
+    pub name: String,
+    pub region: String,
+    /// ISO 3166-1 alpha-2 code.
+    pub country: String,
+}
+
+struct City {
+    place: Place,
+    /// Position on the unit sphere, so straight-line distance orders like great-circle distance
+    /// and nothing special happens at the antimeridian.
+    position: [f64; 3],
+}
+
+fn unit_vector(longitude: f64, latitude: f64) -> [f64; 3] {
+    let (longitude, latitude) = (longitude.to_radians(), latitude.to_radians());
+    [latitude.cos() * longitude.cos(), latitude.cos() * longitude.sin(), latitude.sin()]
+}
+
+fn squared_distance(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    (0..3).map(|i| (a[i] - b[i]).powi(2)).sum()
+}
+
+/// A k-d tree over the cities, stored as a slice where every subslice's middle element
+/// splits the rest along axis `depth % 3`.
+struct Gazetteer {
+    cities: Vec<City>,
+}
+
+impl Gazetteer {
+    fn parse(data: &str) -> Self {
+        let mut cities = data.lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let mut fields = line.split('\t');
+                let place = Place {
+                    name: fields.next()?.to_string(),
+                    region: fields.next()?.to_string(),
+                    country: fields.next()?.to_string(),
+                };
+                let latitude = fields.next()?.parse().ok()?;
+                let longitude = fields.next()?.parse().ok()?;
+                Some(City { place, position: unit_vector(longitude, latitude) })
+            })
+            .collect::<Vec<_>>();
+        build(&mut cities, 0);
+        Self { cities }
+    }
+
+    fn nearest(&self, point: GeoPoint) -> Option<&Place> {
+        let target = unit_vector(point.longitude(), point.latitude());
+        let mut best = None;
+        search(&self.cities, 0, &target, &mut best);
+        let (city, squared) = best?;
+        // Straight-line distance through the sphere to distance along its surface.
+        let km = 2. * (squared.sqrt() / 2.).asin() * EARTH_RADIUS_KM;
+        (km <= MAX_DISTANCE_KM).then_some(&city.place)
+    }
+}
+
+fn build(cities: &mut [City], depth: usize) {
+    if cities.len() <= 1 {
+        return;
+    }
+    let axis = depth % 3;
+    let mid = cities.len() / 2;
+    cities.select_nth_unstable_by(mid, |a, b| a.position[axis].total_cmp(&b.position[axis]));
+    let (left, right) = cities.split_at_mut(mid);
+    build(left, depth + 1);
+    build(&mut right[1..], depth + 1);
+}
+
+fn search<'a>(cities: &'a [City], depth: usize, target: &[f64; 3], best: &mut Option<(&'a City, f64)>) {
+    if cities.is_empty() {
+        return;
+    }
+    let axis = depth % 3;
+    let mid = cities.len() / 2;
+    let city = &cities[mid];
+    let distance = squared_distance(&city.position, target);
+    if best.is_none_or(|(_, best)| distance < best) {
+        *best = Some((city, distance));
+    }
+    let offset = target[axis] - city.position[axis];
+    let (near, far) = if offset < 0. { (&cities[..mid], &cities[mid + 1..]) } else { (&cities[mid + 1..], &cities[..mid]) };
+    search(near, depth + 1, target, best);
+    // The other side can only hold a closer city if the splitting plane is closer than the best so far.
+    if best.is_none_or(|(_, best)| offset.powi(2) < best) {
+        search(far, depth + 1, target, best);
+    }
+}
+
+fn gazetteer() -> &'static Gazetteer {
+    static GAZETTEER: OnceLock<Gazetteer> = OnceLock::new();
+    GAZETTEER.get_or_init(|| Gazetteer::parse(CITIES))
+}
+
+/// Builds the tree, so it happens during the cold start rather than in the first request.
+pub fn load() {
+    gazetteer();
+}
+
+/// The nearest city within [`MAX_DISTANCE_KM`] of `point`.
+pub fn reverse_geocode(point: GeoPoint) -> Option<Place> {
+    gazetteer().nearest(point).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Squared distance to the closest city by checking every one, to compare the tree search against.
+    fn brute_force(gazetteer: &Gazetteer, point: GeoPoint) -> f64 {
+        let target = unit_vector(point.longitude(), point.latitude());
+        gazetteer.cities.iter()
+            .map(|city| squared_distance(&city.position, &target))
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    fn tree_search(gazetteer: &Gazetteer, point: GeoPoint) -> f64 {
+        let mut best = None;
+        search(&gazetteer.cities, 0, &unit_vector(point.longitude(), point.latitude()), &mut best);
+        best.unwrap().1
+    }
+
+    #[test]
+    fn bundled_cities_parse() {
+        let lines = CITIES.lines().filter(|line| !line.is_empty() && !line.starts_with('#')).count();
+        assert_eq!(gazetteer().cities.len(), lines);
+    }
+
+    #[test]
+    fn search_matches_brute_force() {
+        let gazetteer = gazetteer();
+        let mut points = vec![];
+        for longitude in (-180..180).step_by(5) {
+            for latitude in (-90..=90).step_by(5) {
+                points.push((longitude as f64, latitude as f64));
+            }
+        }
+        // The antimeridian and the poles.
+        points.extend([(180., 0.), (179.999999, -17.), (-179.999999, 64.), (0., 90.), (123., 90.), (0., -90.), (-45., -90.)]);
+        for (longitude, latitude) in points {
+            let point = GeoPoint::new(longitude, latitude).unwrap();
+            assert_eq!(tree_search(gazetteer, point), brute_force(gazetteer, point), "{point}");
+        }
+    }
+
+    #[test]
+    fn nearest_crosses_the_antimeridian_and_poles() {
+        let gazetteer = Gazetteer::parse("\
+            # name\tregion\tcountry\tlatitude\tlongitude
+            East\t\tFJ\t0\t179.5
+            West\t\tWS\t0\t-179.8
+            Far\t\tKE\t0\t37
+            North\t\tGL\t89.5\t-60
+            South\t\tAQ\t-89.5\t139
+        ".lines().map(str::trim).collect::<Vec<_>>().join("\n").as_str());
+        let name = |longitude, latitude| gazetteer.nearest(GeoPoint::new(longitude, latitude).unwrap()).map(|it| it.name.as_str());
+        assert_eq!(name(179.9, 0.), Some("West"));
+        assert_eq!(name(180., 0.), Some("West"));
+        assert_eq!(name(-179.4, 0.), Some("West"));
+        assert_eq!(name(179.6, 0.5), Some("East"));
+        assert_eq!(name(120., 90.), Some("North"));
+        assert_eq!(name(120., 89.8), Some("North"));
+        assert_eq!(name(-40., -90.), Some("South"));
+        assert_eq!(name(0., 0.), None);
+        assert_eq!(name(37., 1.), Some("Far"));
+        assert_eq!(name(37., 2.), None);
+    }
+
+    proptest! {
+        #[test]
+        fn nearest_matches_brute_force(longitude in -180f64..=180., latitude in -90f64..=90.) {
+            let gazetteer = gazetteer();
+            let point = GeoPoint::new(longitude, latitude).unwrap();
+            let squared = brute_force(gazetteer, point);
+            prop_assert_eq!(tree_search(gazetteer, point), squared);
+            let km = 2. * (squared.sqrt() / 2.).asin() * EARTH_RADIUS_KM;
+            prop_assert_eq!(gazetteer.nearest(point).is_some(), km <= MAX_DISTANCE_KM);
+            // The place named is one of the closest cities, whichever one wins a tie.
+            if let Some(place) = gazetteer.nearest(point) {
+                let target = unit_vector(point.longitude(), point.latitude());
+                let distance = gazetteer.cities.iter()
+                    .filter(|it| it.place == *place)
+                    .map(|it| squared_distance(&it.position, &target))
+                    .fold(f64::INFINITY, f64::min);
+                prop_assert_eq!(distance, squared);
+            }
+        }
+    }
+}
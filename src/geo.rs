@@ -0,0 +1,305 @@
+use std::{fmt, str::FromStr};
+
+/// A validated position. Longitude is normalized into `[-180, 180)` so both sides of the
+/// antimeridian map to the same regions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeoPoint {
+    longitude: f64,
+    latitude: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeoPointError {
+    /// Not `"longitude,latitude"`.
+    Format,
+    /// NaN or infinite.
+    NotFinite,
+    LongitudeOutOfRange,
+    LatitudeOutOfRange,
+}
+
+impl fmt::Display for GeoPointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Format => "location must be \"longitude,latitude\"",
+            Self::NotFinite => "location coordinates must be finite numbers",
+            Self::LongitudeOutOfRange => "longitude must be between -180 and 180",
+            Self::LatitudeOutOfRange => "latitude must be between -90 and 90",
+        })
+    }
+}
+
+impl GeoPoint {
+    pub fn new(longitude: f64, latitude: f64) -> Result<Self, GeoPointError> {
+        if !longitude.is_finite() || !latitude.is_finite() {
+            return Err(GeoPointError::NotFinite);
+        }
+        if !(-180. ..=180.).contains(&longitude) {
+            return Err(GeoPointError::LongitudeOutOfRange);
+        }
+        if !(-90. ..=90.).contains(&latitude) {
+            return Err(GeoPointError::LatitudeOutOfRange);
+        }
+        // 180 is the same meridian as -180, and adding 0. turns -0. into 0.
+        let longitude = if longitude == 180. { -180. } else { longitude + 0. };
+        Ok(Self { longitude, latitude: latitude + 0. })
+    }
+
+    pub fn longitude(self) -> f64 {
+        self.longitude
+    }
+
+    pub fn latitude(self) -> f64 {
+        self.latitude
+    }
+
+    /// The geohash of the cell containing the point, `precision` characters long.
+    pub fn geohash(self, precision: usize) -> String {
+        let (mut longs, mut lats) = ((-180., 180.), (-90., 90.));
+        let mut hash = String::with_capacity(precision);
+        let mut index = 0;
+        for bit in 0..precision * 5 {
+            // Bits alternate between longitude and latitude, starting with longitude.
+            let (range, value) = if bit % 2 == 0 { (&mut longs, self.longitude) } else { (&mut lats, self.latitude) };
+            let mid = (range.0 + range.1) / 2.;
+            index <<= 1;
+            if value >= mid {
+                index |= 1;
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            if bit % 5 == 4 {
+                hash.push(GEOHASH_ALPHABET[index] as char);
+                index = 0;
+            }
+        }
+        hash
+    }
+
+    /// The geohash cells with `precision` characters overlapping the smallest latitude and
+    /// longitude box that holds every point within `radius_km`.
+    pub fn geohash_cover(self, precision: usize, radius_km: f64) -> Vec<String> {
+        let (long_size, lat_size) = geohash_cell_degrees(precision);
+        let angle = radius_km / EARTH_RADIUS_KM;
+        let (south, north) = ((self.latitude - angle.to_degrees()).max(-90.), (self.latitude + angle.to_degrees()).min(90.));
+        // The widest a circle gets in longitude; it wraps all the way around once it reaches a pole.
+        let ratio = angle.sin() / self.latitude.to_radians().cos();
+        let long_radius = if ratio < 1. { ratio.asin().to_degrees() } else { 180. };
+        let (columns, rows) = ((360. / long_size) as i64, (180. / lat_size) as i64);
+        let column = |longitude: f64| ((longitude + 180.) / long_size).floor() as i64;
+        let row = |latitude: f64| (((latitude + 90.) / lat_size).floor() as i64).min(rows - 1);
+        let (west, east) = (column(self.longitude - long_radius), column(self.longitude + long_radius));
+        let mut cells = Vec::new();
+        for row in row(south)..=row(north) {
+            for column in west..=east.min(west + columns - 1) {
+                let center = Self {
+                    longitude: (column.rem_euclid(columns) as f64 + 0.5) * long_size - 180.,
+                    latitude: (row as f64 + 0.5) * lat_size - 90.,
+                };
+                cells.push(center.geohash(precision));
+            }
+        }
+        cells
+    }
+
+    /// Hides the exact position: snaps to a grid of `cell_degrees` and then moves to a spot in
+    /// that cell derived from `seed`, so the same seed always gives the same point.
+    pub fn obfuscate(self, cell_degrees: f64, seed: &[u8]) -> Self {
+        let hash = openssl::sha::sha256(seed);
+        let fraction = |bytes: &[u8]| u32::from_be_bytes(bytes.try_into().unwrap()) as f64 / u32::MAX as f64;
+        let snap = |value: f64| (value / cell_degrees).floor() * cell_degrees;
+        let latitude = (snap(self.latitude) + fraction(&hash[0..4]) * cell_degrees).clamp(-90., 90.);
+        let longitude = (snap(self.longitude) + fraction(&hash[4..8]) * cell_degrees + 180.).rem_euclid(360.) - 180.;
+        // Five decimals are ~1 m, finer than any cell, and keep the stored string short.
+        let round = |value: f64| (value * 1e5).round() / 1e5;
+        Self::new(round(longitude), round(latitude)).unwrap()
+    }
+
+    /// Great-circle distance in km.
+    pub fn distance_km(self, other: GeoPoint) -> f64 {
+        let (latitude, other_latitude) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let half_lat = (other_latitude - latitude) / 2.;
+        let half_long = (other.longitude - self.longitude).to_radians() / 2.;
+        let a = half_lat.sin().powi(2) + latitude.cos() * other_latitude.cos() * half_long.sin().powi(2);
+        2. * EARTH_RADIUS_KM * a.sqrt().min(1.).asin()
+    }
+
+    /// Distance in degrees, taking the shorter way around the antimeridian.
+    pub fn distance(self, other: GeoPoint) -> f64 {
+        let long_diff = (self.longitude - other.longitude).abs();
+        let long_diff = long_diff.min(360. - long_diff);
+        (long_diff.powi(2) + (self.latitude - other.latitude).powi(2)).sqrt()
+    }
+}
+
+const GEOHASH_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+/// Geohash precisions written to every post, from ~156 km cells down to ~0.6 km cells.
+pub const GEOHASH_PRECISIONS: [usize; 4] = [3, 4, 5, 6];
+pub const EARTH_RADIUS_KM: f64 = 6371.;
+
+/// Width and height in degrees of a geohash cell with `precision` characters.
+fn geohash_cell_degrees(precision: usize) -> (f64, f64) {
+    let bits = precision as i32 * 5;
+    let long_bits = (bits + 1) / 2;
+    (360. / 2f64.powi(long_bits), 180. / 2f64.powi(bits - long_bits))
+}
+
+/// The name of the post attribute, and of the index on it, holding the geohash with `precision` characters.
+pub fn geohash_attribute(precision: usize) -> String {
+    format!("gh{precision}")
+}
+
+impl FromStr for GeoPoint {
+    type Err = GeoPointError;
+
+    fn from_str(location: &str) -> Result<Self, Self::Err> {
+        let (longitude, latitude) = location.split_once(',').ok_or(GeoPointError::Format)?;
+        let longitude = longitude.trim().parse::<f64>().map_err(|_| GeoPointError::Format)?;
+        let latitude = latitude.trim().parse::<f64>().map_err(|_| GeoPointError::Format)?;
+        Self::new(longitude, latitude)
+    }
+}
+
+impl fmt::Display for GeoPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{}", self.longitude, self.latitude)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn rejects_non_finite() {
+        for (longitude, latitude) in [(f64::NAN, 0.), (0., f64::NAN), (f64::INFINITY, 0.), (0., f64::NEG_INFINITY)] {
+            assert_eq!(GeoPoint::new(longitude, latitude), Err(GeoPointError::NotFinite));
+        }
+        assert_eq!("NaN,0".parse::<GeoPoint>(), Err(GeoPointError::NotFinite));
+        assert_eq!("0,inf".parse::<GeoPoint>(), Err(GeoPointError::NotFinite));
+    }
+
+    #[test]
+    fn rejects_out_of_range() {
+        assert_eq!(GeoPoint::new(180.000001, 0.), Err(GeoPointError::LongitudeOutOfRange));
+        assert_eq!(GeoPoint::new(-180.000001, 0.), Err(GeoPointError::LongitudeOutOfRange));
+        assert_eq!(GeoPoint::new(0., 90.000001), Err(GeoPointError::LatitudeOutOfRange));
+        assert_eq!(GeoPoint::new(0., -90.000001), Err(GeoPointError::LatitudeOutOfRange));
+        assert!(GeoPoint::new(-180., -90.).is_ok());
+        assert!(GeoPoint::new(180., 90.).is_ok());
+    }
+
+    #[test]
+    fn normalizes_antimeridian_and_negative_zero() {
+        let point = GeoPoint::new(180., 10.).unwrap();
+        assert_eq!(point.longitude(), -180.);
+        assert_eq!(point, GeoPoint::new(-180., 10.).unwrap());
+        let point = GeoPoint::new(-0., -0.).unwrap();
+        assert!(point.longitude().is_sign_positive() && point.latitude().is_sign_positive());
+        assert_eq!(point.to_string(), "0,0");
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for (longitude, latitude) in [(0., 0.), (-122.4194, 37.7749), (151.20929, -33.86882), (-180., -90.), (179.99999, 89.99999), (0.1 + 0.2, 1e-7)] {
+            let point = GeoPoint::new(longitude, latitude).unwrap();
+            assert_eq!(point.to_string().parse::<GeoPoint>(), Ok(point));
+        }
+        // A sweep over the whole valid range with awkward fractions.
+        for i in 0..=3600 {
+            let point = GeoPoint::new(-180. + i as f64 * 0.1 / 1.000_7, -90. + i as f64 * 0.05 / 1.000_3).unwrap();
+            assert_eq!(point.to_string().parse::<GeoPoint>(), Ok(point));
+            assert!((-180. ..180.).contains(&point.longitude()));
+        }
+        assert_eq!(" 13.4 , 52.5 ".parse::<GeoPoint>(), GeoPoint::new(13.4, 52.5));
+        assert_eq!("13.4".parse::<GeoPoint>(), Err(GeoPointError::Format));
+        assert_eq!("13.4,north".parse::<GeoPoint>(), Err(GeoPointError::Format));
+    }
+
+    #[test]
+    fn distance_wraps_around_antimeridian() {
+        let east = GeoPoint::new(179.5, 0.).unwrap();
+        let west = GeoPoint::new(-179.5, 0.).unwrap();
+        assert!((east.distance(west) - 1.).abs() < 1e-9);
+        assert!((west.distance(east) - 1.).abs() < 1e-9);
+        let north_east = GeoPoint::new(179., 3.).unwrap();
+        let south_west = GeoPoint::new(-178., -1.).unwrap();
+        assert!((north_east.distance(south_west) - 5.).abs() < 1e-9);
+        assert_eq!(east.distance(east), 0.);
+    }
+
+    #[test]
+    fn distance_km() {
+        let berlin = GeoPoint::new(13.405, 52.52).unwrap();
+        let paris = GeoPoint::new(2.3522, 48.8566).unwrap();
+        assert!((berlin.distance_km(paris) - 878.).abs() < 2.);
+        let east = GeoPoint::new(179.5, 0.).unwrap();
+        let west = GeoPoint::new(-179.5, 0.).unwrap();
+        assert!((east.distance_km(west) - 111.19).abs() < 0.1);
+    }
+
+    #[test]
+    fn geohash_cover_holds_every_point_in_radius() {
+        let centers = [(13.405, 52.52), (179.9, 0.), (-179.95, -45.), (0., 89.5), (100., -89.9), (-73.9, 40.7)];
+        for (longitude, latitude) in centers {
+            let center = GeoPoint::new(longitude, latitude).unwrap();
+            for (precision, radius_km) in [(3, 150.), (4, 25.), (5, 5.)] {
+                let cover = center.geohash_cover(precision, radius_km);
+                let unique = cover.iter().collect::<std::collections::HashSet<_>>();
+                assert_eq!(unique.len(), cover.len(), "{center} {precision}");
+                // Points on circles just inside the radius.
+                for step in 0..72 {
+                    let bearing = (step as f64 * 5.).to_radians();
+                    let angle = radius_km * 0.999 / EARTH_RADIUS_KM;
+                    let latitude = center.latitude.to_radians();
+                    let point_latitude = (latitude.sin() * angle.cos() + latitude.cos() * angle.sin() * bearing.cos()).asin();
+                    let point_longitude = center.longitude.to_radians()
+                        + (bearing.sin() * angle.sin() * latitude.cos()).atan2(angle.cos() - latitude.sin() * point_latitude.sin());
+                    let longitude = (point_longitude.to_degrees() + 180.).rem_euclid(360.) - 180.;
+                    let point = GeoPoint::new(longitude, point_latitude.to_degrees()).unwrap();
+                    assert!(center.distance_km(point) <= radius_km);
+                    assert!(cover.contains(&point.geohash(precision)), "{center} {precision} {point}");
+                }
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn any_valid_point_round_trips(longitude in -180f64..=180., latitude in -90f64..=90.) {
+            let point = GeoPoint::new(longitude, latitude).unwrap();
+            prop_assert_eq!(point.to_string().parse::<GeoPoint>(), Ok(point));
+        }
+
+        #[test]
+        fn normalizes_longitude(longitude in -180f64..=180., latitude in -90f64..=90.) {
+            let point = GeoPoint::new(longitude, latitude).unwrap();
+            prop_assert!((-180. ..180.).contains(&point.longitude()));
+            prop_assert!(point.longitude() == longitude || longitude == 180. && point.longitude() == -180.);
+            prop_assert_eq!(point.latitude(), latitude);
+        }
+
+        #[test]
+        fn rejects_out_of_range_longitude(longitude in prop_oneof![f64::MIN..-180., 180f64..f64::MAX].prop_filter("in range", |it| it.abs() > 180.), latitude in -90f64..=90.) {
+            prop_assert_eq!(GeoPoint::new(longitude, latitude), Err(GeoPointError::LongitudeOutOfRange));
+            prop_assert_eq!(format!("{longitude},{latitude}").parse::<GeoPoint>(), Err(GeoPointError::LongitudeOutOfRange));
+        }
+
+        #[test]
+        fn rejects_out_of_range_latitude(longitude in -180f64..=180., latitude in prop_oneof![f64::MIN..-90., 90f64..f64::MAX].prop_filter("in range", |it| it.abs() > 90.)) {
+            prop_assert_eq!(GeoPoint::new(longitude, latitude), Err(GeoPointError::LatitudeOutOfRange));
+            prop_assert_eq!(format!("{longitude},{latitude}").parse::<GeoPoint>(), Err(GeoPointError::LatitudeOutOfRange));
+        }
+
+        #[test]
+        fn rejects_any_non_finite(finite in any::<f64>().prop_filter("not finite", |it| it.is_finite()), other in prop_oneof![Just(f64::NAN), Just(f64::INFINITY), Just(f64::NEG_INFINITY)]) {
+            prop_assert_eq!(GeoPoint::new(other, finite), Err(GeoPointError::NotFinite));
+            prop_assert_eq!(GeoPoint::new(finite, other), Err(GeoPointError::NotFinite));
+            prop_assert_eq!(format!("{other},{finite}").parse::<GeoPoint>(), Err(GeoPointError::NotFinite));
+            prop_assert_eq!(format!("{finite},{other}").parse::<GeoPoint>(), Err(GeoPointError::NotFinite));
+        }
+    }
+}
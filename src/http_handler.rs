@@ -1,145 +1,117 @@
-use std::time::{Duration, SystemTime};
+use std::time::Instant;
 
-use lambda_http::{Body, Error, Request, RequestExt, Response};
-use openssl::{base64, error::ErrorStack, hash::MessageDigest, nid::Nid, pkey::{PKey, Public}, rsa::Rsa, sign::Verifier, x509::X509};
+use lambda_http::{http::{HeaderValue, Method}, tracing::{self, Instrument, Span}, Body, Error, Request, RequestExt, Response};
 
-use crate::{info_upload::info_upload, media_upload::media_upload_url, post_download::{get_info, get_media_url}, recommendations::recommend_posts};
+use crate::{api_error::ApiError, auth::{authenticate, has_credentials, Caller}, cert_revocation::revoke_cert, cors::{add_cors_headers, allowed_origin, is_preflight, preflight_response}, info_upload::info_upload, media_upload::media_upload_url, post_download::{get_info, get_media_url}, post_delete::delete_post, post_edit::edit_post, rate_limit::{ip_rate_limit_key, user_rate_limit_key, RateLimit, RateLimiter}, recommendations::recommend_posts, router::{allow_header, match_route, Auth, Route, RouteMatch}, session::create_session};
+
+const POST_LIMIT: RateLimit = RateLimit { burst: 10, per_minute: 5 };
+const UPLOAD_LIMIT: RateLimit = RateLimit { burst: 20, per_minute: 10 };
+const READ_LIMIT: RateLimit = RateLimit { burst: 60, per_minute: 120 };
+const ACCOUNT_LIMIT: RateLimit = RateLimit { burst: 5, per_minute: 5 };
+/// Shared by every request from one source IP across all routes, checked before authentication
+/// so bad credentials can't be tried without limit. Large enough for many users behind one NAT.
+const IP_LIMIT: RateLimit = RateLimit { burst: 300, per_minute: 600 };
+
+static ROUTES: &[Route] = &[
+    Route { method: Method::POST, pattern: "/post-info", auth: Auth::Required, rate_limit: Some(POST_LIMIT), handler: |event| Box::pin(info_upload(event)) },
+    Route { method: Method::POST, pattern: "/post-media", auth: Auth::Required, rate_limit: Some(UPLOAD_LIMIT), handler: |event| Box::pin(media_upload_url(event)) },
+    Route { method: Method::GET, pattern: "/get-info", auth: Auth::Optional, rate_limit: Some(READ_LIMIT), handler: |event| Box::pin(get_info(event)) },
+    Route { method: Method::GET, pattern: "/posts/{id}", auth: Auth::Optional, rate_limit: Some(READ_LIMIT), handler: |event| Box::pin(get_info(event)) },
+    Route { method: Method::PATCH, pattern: "/posts/{id}", auth: Auth::Required, rate_limit: Some(POST_LIMIT), handler: |event| Box::pin(edit_post(event)) },
+    Route { method: Method::DELETE, pattern: "/posts/{id}", auth: Auth::Required, rate_limit: Some(POST_LIMIT), handler: |event| Box::pin(delete_post(event)) },
+    Route { method: Method::GET, pattern: "/get-media", auth: Auth::Optional, rate_limit: Some(READ_LIMIT), handler: |event| Box::pin(get_media_url(event)) },
+    Route { method: Method::GET, pattern: "/posts/{id}/media/{index}", auth: Auth::Optional, rate_limit: Some(READ_LIMIT), handler: |event| Box::pin(get_media_url(event)) },
+    Route { method: Method::GET, pattern: "/recommendations", auth: Auth::Optional, rate_limit: Some(READ_LIMIT), handler: |event| Box::pin(recommend_posts(event)) },
+    Route { method: Method::POST, pattern: "/revoke-cert", auth: Auth::Required, rate_limit: Some(ACCOUNT_LIMIT), handler: |event| Box::pin(revoke_cert(event)) },
+    Route { method: Method::POST, pattern: "/auth/session", auth: Auth::Certificate, rate_limit: Some(ACCOUNT_LIMIT), handler: |event| Box::pin(create_session(event)) },
+];
 
 /// This is the main body for the function.
 /// Write your code inside it.
 /// There are some code example in the following URLs:
 /// - https://github.com/awslabs/aws-lambda-rust-runtime/tree/main/examples
 pub(crate) async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
-    let Some(Ok(username)) = event.headers().get("X-Username").map(|it| it.to_str()) else {
-        return Ok(Response::builder()
-            .status(400)
-            .header("content-type", "text/plain")
-            .body("400 - Missing Header".into())
-            .map_err(Box::new)?);
-    };
-    let Some(Ok(cert_str)) = event.headers().get("X-Auth-Cert").map(|it| it.to_str()) else {
-        return Ok(Response::builder()
-            .status(400)
-            .header("content-type", "text/plain")
-            .body("400 - Missing Header".into())
-            .map_err(Box::new)?);
-    };
-    let Some(Ok(sig_str)) = event.headers().get("X-Auth-Signature").map(|it| it.to_str()) else {
-        return Ok(Response::builder()
-            .status(400)
-            .header("content-type", "text/plain")
-            .body("400 - Missing Header".into())
-            .map_err(Box::new)?);
+    let started = Instant::now();
+    let request_id = event.lambda_context_ref().map(|it| it.request_id.clone()).unwrap_or_default();
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %event.method(),
+        path = event.raw_http_path(),
+        route = tracing::field::Empty,
+        username = tracing::field::Empty,
+    );
+    let origin = allowed_origin(&event);
+    let mut response = match handle(event).instrument(span.clone()).await {
+        Ok(response) => response,
+        Err(e) => e.into_response(&request_id),
     };
-    let Some(Ok(nonce)) = event.headers().get("X-Nonce").map(|it| it.to_str()) else {
-        return Ok(Response::builder()
-            .status(400)
-            .header("content-type", "text/plain")
-            .body("400 - Missing Header".into())
-            .map_err(Box::new)?);
-    };
-    let cert = X509::from_der(&base64::decode_block(cert_str).unwrap()).unwrap();
-    let signature = base64::decode_block(sig_str).unwrap();
-    let cert_pub_key = cert.public_key().unwrap();
-    let mut verifier = Verifier::new(MessageDigest::sha256(), &cert_pub_key).unwrap();
-    let reqw_client = reqwest::Client::new();
-    let pub_key_req = reqw_client.get("https://social-media-account-provisioning-public-key.s3.us-west-2.amazonaws.com/server_public_key.der").build().unwrap();
-    let pub_key_bytes = base64::decode_block(&String::from_utf8(reqw_client.execute(pub_key_req).await.unwrap().bytes().await.unwrap().to_vec()).unwrap()).unwrap();
-    let pub_key = PKey::from_rsa(Rsa::public_key_from_der(&pub_key_bytes).unwrap()).unwrap();
-    println!("inputs good");
-
-
-    if let Ok(true) = cert.verify(&pub_key) {} else {
-        return Ok(Response::builder()
-            .status(401)
-            .header("content-type", "text/plain")
-            .body("401 - Unauthorized".into())
-            .map_err(Box::new)?);
+    add_cors_headers(&mut response, origin.as_deref());
+    if let Ok(request_id) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert("X-Request-Id", request_id);
     }
-    println!("cert valid");
-    if let Ok(true) = verify_cert(&cert, username, &pub_key) {} else {
-        return Ok(Response::builder()
-            .status(401)
-            .header("content-type", "text/plain")
-            .body("401 - Unauthorized".into())
-            .map_err(Box::new)?);
+    span.in_scope(|| tracing::info!(
+        status = response.status().as_u16(),
+        latency_ms = started.elapsed().as_millis() as u64,
+        "request finished",
+    ));
+    Ok(response)
+}
+
+async fn handle(event: Request) -> Result<Response<Body>, ApiError> {
+    let (route, params) = match match_route(ROUTES, event.method(), event.raw_http_path()) {
+        RouteMatch::Found(route, params) => (route, params),
+        RouteMatch::MethodNotAllowed(allowed) if is_preflight(&event) => {
+            return Ok(preflight_response(&allowed));
+        }
+        RouteMatch::MethodNotAllowed(allowed) => {
+            return Err(ApiError::new(405, "method_not_allowed", format!("{} is not allowed here", event.method()))
+                .with_header("Allow", allow_header(&allowed)));
+        }
+        RouteMatch::NotFound => {
+            return Err(ApiError::not_found("route_not_found", "no such route"));
+        }
+    };
+    Span::current().record("route", route.pattern);
+    let ip_key = ip_rate_limit_key(&event);
+    if let Some(ip_key) = &ip_key {
+        check_rate_limit(ip_key, IP_LIMIT).await?;
     }
-    println!("username matches cert");
-    println!("nonce: {}", nonce);
-    if let Ok(timestamp_millis) = nonce.parse::<u64>() {
-        let now = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
-        let timestamp = Duration::from_millis(timestamp_millis);
-        if timestamp > now {
-            if timestamp - now > Duration::from_secs(60) {
-                return Ok(Response::builder()
-                .status(401)
-                .header("content-type", "text/plain")
-                .body("401 - Unauthorized".into())
-                .map_err(Box::new)?);
+    let caller = if route.auth == Auth::Optional && !has_credentials(&event) {
+        tracing::info!(auth = "anonymous");
+        Caller::Anonymous
+    } else {
+        match authenticate(&event, route.auth != Auth::Certificate).await {
+            Ok(identity) => {
+                Span::current().record("username", identity.username.as_str());
+                tracing::info!(auth = "ok");
+                Caller::User(identity)
             }
-        } else {
-            if now - timestamp > Duration::from_secs(60) {
-                return Ok(Response::builder()
-                    .status(401)
-                    .header("content-type", "text/plain")
-                    .body("401 - Unauthorized".into())
-                    .map_err(Box::new)?);
+            Err(e) => {
+                tracing::warn!(auth = "failed", reason = e.reason(), error = %e);
+                return Err(e.into());
             }
         }
-    } else {
-        println!("nonce not valid number");
-        return Ok(Response::builder()
-            .status(401)
-            .header("content-type", "text/plain")
-            .body("401 - Unauthorized".into())
-            .map_err(Box::new)?);
-    }
-    println!("timestamp valid");
-    let mut payload = vec![];
-    payload.append(&mut username.as_bytes().to_vec());
-    payload.append(&mut nonce.as_bytes().to_vec());
-    verifier.update(&payload).unwrap();
-    if let Ok(true) = verifier.verify(&signature) {} else {
-        return Ok(Response::builder()
-            .status(401)
-            .header("content-type", "text/plain")
-            .body("401 - Unauthorized".into())
-            .map_err(Box::new)?);
-    }
-    println!("signature valid");
-    println!("{}", event.raw_http_path());
-    if event.raw_http_path() == "/post-info" {
-        return info_upload(event).await;
-    }
-    if event.raw_http_path() == "/post-media" {
-        return media_upload_url(event).await;
-    }
-    if event.raw_http_path() == "/get-info" {
-        return get_info(event).await;
-    }
-    if event.raw_http_path() == "/get-media" {
-        return get_media_url(event).await;
-    }
-    if event.raw_http_path() == "/recommendations" {
-        return recommend_posts(event).await;
+    };
+    // The route's own limit applies per user, and per source IP to anonymous callers.
+    let caller_key = match caller.username() {
+        Some(username) => Some(user_rate_limit_key(username)),
+        None => ip_key,
+    };
+    if let (Some(limit), Some(caller_key)) = (route.rate_limit, caller_key) {
+        check_rate_limit(&format!("{} {}#{caller_key}", route.method, route.pattern), limit).await?;
     }
 
-    let resp = Response::builder()
-        .status(404)
-        .header("content-type", "text/plain")
-        .body("404 - Not Found".into())
-        .map_err(Box::new)?;
-    Ok(resp)
+    let mut event = event.with_path_parameters(params);
+    event.extensions_mut().insert(caller);
+    (route.handler)(event).await
 }
 
-fn verify_cert(cert: &X509, username: &str, server_pub_key: &PKey<Public>) -> Result<bool, ErrorStack> {
-    if !cert.verify(server_pub_key)? {
-        return Ok(false);
+/// Takes a token from the bucket for `key`, failing with 429 when it is empty.
+async fn check_rate_limit(key: &str, limit: RateLimit) -> Result<(), ApiError> {
+    if let Some(retry_after) = RateLimiter::from_env().await?.check(key, limit).await? {
+        return Err(ApiError::new(429, "rate_limited", "too many requests")
+            .with_header("Retry-After", retry_after.as_millis().div_ceil(1000).to_string()));
     }
-    if let Some(field) = cert.subject_name().entries_by_nid(Nid::ACCOUNT).next() {
-        if field.data().as_utf8()?.to_string() == username {
-            return Ok(true);
-        }
-    }
-    Ok(false)
-}
\ No newline at end of file
+    Ok(())
+}
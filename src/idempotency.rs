@@ -0,0 +1,125 @@
+use std::time::{Duration, SystemTime};
+
+use aws_sdk_dynamodb::{operation::put_item::PutItemError, primitives::Blob, types::AttributeValue};
+use lambda_http::{Body, Request, Response};
+
+use crate::{api_error::ApiError, info_upload::DynamoDBClient};
+
+const IDEMPOTENCY_TABLE: &str = "SocialMediaIdempotency";
+/// How long a stored response is replayed for the same key.
+pub const IDEMPOTENCY_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+const MAX_KEY_LEN: usize = 255;
+/// How long a request holds its key while being handled. Longer than API Gateway's 29 second
+/// integration timeout, so once it passes the request that took the key is gone, e.g. because
+/// the function timed out or crashed, and a retry may take the key over.
+const LOCK_LEASE: Duration = Duration::from_secs(30);
+
+/// An `Idempotency-Key` sent with a request. Keys are scoped to the caller and the route, and
+/// remembered in the `SocialMediaIdempotency` table, which expires them through its `expires` TTL attribute.
+pub struct IdempotencyKey {
+    id: String,
+    /// Hex SHA-256 of the request body, so a key can't be reused for a different request.
+    request_hash: String,
+}
+
+impl IdempotencyKey {
+    /// Returns `None` if the request has no `Idempotency-Key` header.
+    pub fn from_request(event: &Request, username: &str) -> Result<Option<Self>, ApiError> {
+        let Some(key) = event.headers().get("Idempotency-Key") else {
+            return Ok(None);
+        };
+        let key = match key.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => key,
+            _ => return Err(ApiError::bad_request("invalid_idempotency_key", format!("Idempotency-Key must be 1 to {MAX_KEY_LEN} visible ASCII characters"))),
+        };
+        let request_hash = openssl::sha::sha256(event.body()).iter().map(|b| format!("{b:02x}")).collect();
+        Ok(Some(Self {
+            id: format!("{username}#{}#{key}", event.uri().path()),
+            request_hash,
+        }))
+    }
+
+    /// Marks the key as in progress for [`LOCK_LEASE`]. Returns the stored response if a request
+    /// with this key already finished, which the caller should send instead of handling the request again.
+    /// A key whose lease ran out without a response is taken over, as is one past its expiry
+    /// that the TTL has not removed yet.
+    pub async fn begin(&self, client: &DynamoDBClient) -> Result<Option<Response<Body>>, ApiError> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        let result = client.client.put_item()
+            .table_name(IDEMPOTENCY_TABLE)
+            .item("id", AttributeValue::S(self.id.clone()))
+            .item("request_hash", AttributeValue::S(self.request_hash.clone()))
+            .item("status", AttributeValue::S("pending".into()))
+            .item("locked_until", AttributeValue::N((now + LOCK_LEASE).as_millis().to_string()))
+            .item("expires", AttributeValue::N((now + IDEMPOTENCY_TTL).as_secs().to_string()))
+            .condition_expression("attribute_not_exists(id) \
+                OR (#status = :pending AND locked_until < :now_millis AND request_hash = :request_hash) \
+                OR expires < :now_secs")
+            .expression_attribute_names("#status", "status")
+            .expression_attribute_values(":pending", AttributeValue::S("pending".into()))
+            .expression_attribute_values(":now_millis", AttributeValue::N(now.as_millis().to_string()))
+            .expression_attribute_values(":now_secs", AttributeValue::N(now.as_secs().to_string()))
+            .expression_attribute_values(":request_hash", AttributeValue::S(self.request_hash.clone()))
+            .send()
+            .await;
+        match result {
+            Ok(_) => return Ok(None),
+            Err(e) => match e.into_service_error() {
+                PutItemError::ConditionalCheckFailedException(_) => {}
+                e => return Err(e.into()),
+            },
+        }
+
+        let item = client.client.get_item()
+            .table_name(IDEMPOTENCY_TABLE)
+            .key("id", AttributeValue::S(self.id.clone()))
+            .consistent_read(true)
+            .send()
+            .await?
+            .item
+            .unwrap_or_default();
+        if item.get("request_hash").and_then(|it| it.as_s().ok()) != Some(&self.request_hash) {
+            return Err(ApiError::new(422, "idempotency_key_reused", "Idempotency-Key was already used for a different request"));
+        }
+        let status = item.get("response_status").and_then(|it| it.as_n().ok()?.parse::<u16>().ok());
+        let body = item.get("response_body").and_then(|it| it.as_b().ok());
+        let (Some(status), Some(body)) = (status, body) else {
+            // Until the lease runs out and the key can be taken over.
+            let locked_until = item.get("locked_until").and_then(|it| it.as_n().ok()?.parse::<u64>().ok()).unwrap_or_default();
+            let retry_after = Duration::from_millis(locked_until).saturating_sub(now).as_millis().div_ceil(1000).max(1);
+            return Err(ApiError::new(409, "request_in_progress", "a request with this Idempotency-Key is still being processed")
+                .with_header("Retry-After", retry_after.to_string()));
+        };
+        Ok(Some(Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .header("Idempotent-Replayed", "true")
+            .body(Body::from(body.as_ref().to_vec()))
+            .unwrap()))
+    }
+
+    /// Stores the response so retries with the same key get it back.
+    pub async fn complete(&self, client: &DynamoDBClient, response: &Response<Body>) -> Result<(), ApiError> {
+        client.client.update_item()
+            .table_name(IDEMPOTENCY_TABLE)
+            .key("id", AttributeValue::S(self.id.clone()))
+            .update_expression("SET #status = :done, response_status = :response_status, response_body = :response_body REMOVE locked_until")
+            .expression_attribute_names("#status", "status")
+            .expression_attribute_values(":done", AttributeValue::S("done".into()))
+            .expression_attribute_values(":response_status", AttributeValue::N(response.status().as_u16().to_string()))
+            .expression_attribute_values(":response_body", AttributeValue::B(Blob::new(response.body().as_ref())))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Forgets the key after the request failed, so the client can retry it.
+    pub async fn abort(&self, client: &DynamoDBClient) -> Result<(), ApiError> {
+        client.client.delete_item()
+            .table_name(IDEMPOTENCY_TABLE)
+            .key("id", AttributeValue::S(self.id.clone()))
+            .send()
+            .await?;
+        Ok(())
+    }
+}
@@ -1,97 +1,103 @@
 use std::{collections::HashMap, time::SystemTime};
 
 use aws_config::{load_defaults, BehaviorVersion};
-use aws_sdk_dynamodb::{types::AttributeValue, Client};
-use lambda_http::{Body, Error, Request, Response};
-
-#[derive(serde::Deserialize)]
-struct PostInfo {
-    content_id: String,
-    location: String,
-    username: String,
-}
+use aws_sdk_dynamodb::{operation::put_item::PutItemError, types::AttributeValue, Client};
+use aws_sdk_s3::error::DisplayErrorContext;
+use lambda_http::{tracing, Body, Error, Request, Response};
+
+use crate::{api_error::ApiError, auth::Caller, geo::{geohash_attribute, GeoPoint, GEOHASH_PRECISIONS}, idempotency::IdempotencyKey, post_model::{NewPost, PostRecord, PostStatus}, upload_reservation::{claim, release}};
 
-pub async fn info_upload(event: Request) -> Result<Response<Body>, Error> {
-    let username_header = event.headers().get("X-Username").unwrap().to_str().unwrap().to_string();
-    let Ok(info_string) = String::from_utf8(event.into_body().to_vec()) else {
-        return Ok(Response::builder()
-            .status(400)
-            .body(Body::from("Invalid body"))
-            .unwrap());
+/// Creates a post. Sending an `Idempotency-Key` header makes retries of the same request
+/// return the original response instead of failing because the post already exists.
+pub async fn info_upload(event: Request) -> Result<Response<Body>, ApiError> {
+    let username = Caller::of(&event).username().unwrap().to_string();
+    let client = DynamoDBClient::new().await?;
+    let Some(idempotency_key) = IdempotencyKey::from_request(&event, &username)? else {
+        return create_post(&client, &event, &username).await;
     };
-    let Ok(info) = serde_json::from_str::<PostInfo>(&info_string) else {
-        return Ok(Response::builder()
-            .status(400)
-            .body(Body::from("Invalid body"))
-            .unwrap());
+    if let Some(response) = idempotency_key.begin(&client).await? {
+        return Ok(response);
+    }
+    let result = create_post(&client, &event, &username).await;
+    match &result {
+        Ok(response) => idempotency_key.complete(&client, response).await?,
+        Err(_) => idempotency_key.abort(&client).await?,
+    }
+    result
+}
+
+async fn create_post(dynamo: &DynamoDBClient, event: &Request, username_header: &str) -> Result<Response<Body>, ApiError> {
+    let info = match serde_json::from_slice::<NewPost>(event.body()) {
+        Ok(info) => info,
+        Err(e) => return Err(ApiError::bad_request("invalid_body", e.to_string())),
     };
+    info.validate()?;
     if info.username != username_header {
-        return Ok(Response::builder()
-            .status(401)
-            .body(Body::from("401 - Unauthorized"))
-            .unwrap());
+        return Err(ApiError::forbidden("username_mismatch", "post username does not match the caller"));
     }
     let config = load_defaults(BehaviorVersion::latest()).await;
     let client = aws_sdk_s3::Client::new(&config);
 
-    if !check_file_exists(&client, "social-media-post-media", &info.content_id).await {
-        return Ok(Response::builder()
-            .status(404)
-            .body(Body::from("Content not found"))
-            .unwrap());
-    
+    for item in info.media_items() {
+        if !check_file_exists(&client, "social-media-post-media", &item.content_id).await {
+            return Err(ApiError::not_found("content_not_found", format!("no uploaded media with content id {}", item.content_id)));
+        }
     }
 
-    let Some((r_long, r_lat)) = get_region_i64(&info.location) else {
-        return Ok(Response::builder()
-            .status(400)
-            .body(Body::from("Invalid location"))
-            .unwrap());
+    let location = match info.location.parse::<GeoPoint>() {
+        Ok(location) => location,
+        Err(e) => return Err(ApiError::bad_request("invalid_location", e.to_string())),
     };
     let now = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
 
-    let client = DynamoDBClient::new().await?;
-    let mut item = HashMap::new();
-    item.insert("id".into(), AttributeValue::S(info.content_id));
-    item.insert("r_long".into(), AttributeValue::S(r_long.to_string()));
-    item.insert("r_lat".into(), AttributeValue::S(r_lat.to_string()));
-    item.insert("region".into(), AttributeValue::S(format!("{r_long},{r_lat}")));
-    item.insert("location".into(), AttributeValue::S(info.location));
-    item.insert("info".into(), AttributeValue::S(info_string));
-    item.insert("date".into(), AttributeValue::N(now.as_millis().to_string()));
-    if let Err(_e) = client.put_item("SocialMediaPosts", item).await {
-        return Ok(Response::builder()
-            .status(500)
-            .body(Body::from("Mb :("))
-            .unwrap());
+    let post = PostRecord::new(info, location, now.as_millis() as u64);
+    for (i, media) in post.media.iter().enumerate() {
+        if let Err(e) = claim(dynamo, &media.content_id, &post.username, &post.id).await {
+            release_media(dynamo, &post, i).await?;
+            return Err(e);
+        }
+    }
+    let mut item = post.to_item();
+    // Drafts and scheduled posts stay out of the recommendation indexes until they are published.
+    // They are built from the public location, so search results can't be used to narrow it down.
+    if post.status == PostStatus::Published {
+        item.extend(geohash_attributes(post.location_precision.public_location(location, &post.id)));
+    }
+    match dynamo.put_new_item("SocialMediaPosts", "id", item).await {
+        Ok(true) => {}
+        Ok(false) => {
+            release_media(dynamo, &post, post.media.len()).await?;
+            return Err(ApiError::new(409, "post_exists", format!("post {} already exists", post.id)));
+        }
+        Err(e) => {
+            release_media(dynamo, &post, post.media.len()).await?;
+            return Err(e.into());
+        }
     }
 
     Ok(Response::builder()
             .status(200)
-            .body(Body::from(()))
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_string(&post)?))
             .unwrap())
 }
 
-pub fn get_region(location: &str) -> Option<String> {
-    let Some((long, lat)) = get_region_i64(location) else { return None; };
-    Some(format!("{long},{lat}"))
+/// Releases the uploads of the first `claimed` media items of a post that could not be created.
+async fn release_media(dynamo: &DynamoDBClient, post: &PostRecord, claimed: usize) -> Result<(), ApiError> {
+    for media in &post.media[..claimed] {
+        release(dynamo, &media.content_id, &post.id).await?;
+    }
+    Ok(())
 }
 
-pub fn get_region_i64(location: &str) -> Option<(i64, i64)> {
-    let Some((longitude_str, latitude_str)) = location.split_once(',') else {
-        return None;
-    };
-    let Ok(longitude) = longitude_str.parse::<f64>() else {
-        return None;
-    };
-    let Ok(latitude) = latitude_str.parse::<f64>() else {
-        return None;
-    };
-    Some((longitude.round() as i64, latitude.round() as i64))
+/// The geohash of a post's location at every indexed precision, queried by `recommend_posts`.
+pub fn geohash_attributes(location: GeoPoint) -> HashMap<String, AttributeValue> {
+    GEOHASH_PRECISIONS.iter()
+        .map(|&precision| (geohash_attribute(precision), AttributeValue::S(location.geohash(precision))))
+        .collect()
 }
 
 async fn check_file_exists(client: &aws_sdk_s3::Client, bucket: &str, key: &str) -> bool {
-    println!("checking {bucket} / {key}");
     match client
         .head_object()
         .bucket(bucket)
@@ -101,8 +107,8 @@ async fn check_file_exists(client: &aws_sdk_s3::Client, bucket: &str, key: &str)
     {
         Ok(_) => true,
         Err(e) => {
-            println!("{e:?}");
-            return false;
+            tracing::warn!(bucket, key, error = %DisplayErrorContext(&e), "media lookup failed");
+            false
         },
     }
 }
@@ -129,6 +135,26 @@ impl DynamoDBClient {
         Ok(())
     }
 
+    /// Like [`Self::put_item`], but never replaces an existing item.
+    /// Returns `false` if an item with the same `key_name` value already exists.
+    pub async fn put_new_item(&self, table_name: &str, key_name: &str, item: HashMap<String, AttributeValue>) -> Result<bool, Error> {
+        let result = self.client
+            .put_item()
+            .table_name(table_name)
+            .set_item(Some(item))
+            .condition_expression("attribute_not_exists(#key)")
+            .expression_attribute_names("#key", key_name)
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) => match e.into_service_error() {
+                PutItemError::ConditionalCheckFailedException(_) => Ok(false),
+                e => Err(e.into()),
+            },
+        }
+    }
+
     pub async fn get_item(
         &self,
         table_name: &str,
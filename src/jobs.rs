@@ -0,0 +1,284 @@
+use std::{collections::HashMap, time::SystemTime};
+
+use aws_sdk_dynamodb::{operation::update_item::UpdateItemError, types::AttributeValue};
+use lambda_http::{tracing, Error};
+
+use crate::{gazetteer::reverse_geocode, geo::GeoPoint, info_upload::{geohash_attributes, DynamoDBClient}, post_delete::{delete_media, TOMBSTONE_RETENTION}, post_model::{place_to_attribute, LocationPrecision, PostStatus}};
+
+/// Runs a maintenance job, either once from the command line, e.g.
+/// `social-media-post-upload backfill-geohash`, or on every invocation by a schedule (see `main`).
+pub async fn run(name: &str) -> Result<(), Error> {
+    match name {
+        "backfill-geohash" => backfill_geohash().await,
+        "purge-deleted" => purge_deleted().await,
+        "publish-scheduled" => publish_scheduled().await,
+        "coarsen-legacy-locations" => coarsen_legacy_locations().await,
+        _ => Err(format!("unknown job {name:?}").into()),
+    }
+}
+
+/// Adds the geohash attributes to posts created before they were written at upload time,
+/// and removes the whole-degree `r_long`/`r_lat`/`region` attributes they replaced.
+/// Deleted and unpublished posts are skipped so they stay out of the geohash indexes.
+async fn backfill_geohash() -> Result<(), Error> {
+    let client = DynamoDBClient::new().await?;
+    let (mut updated, mut skipped) = (0, 0);
+    let mut start_key: Option<HashMap<String, AttributeValue>> = None;
+    loop {
+        let page = client.client.scan()
+            .table_name("SocialMediaPosts")
+            .projection_expression("id, #location")
+            .filter_expression("attribute_not_exists(deleted_at) AND (attribute_not_exists(#status) OR #status = :published)")
+            .expression_attribute_names("#location", "location")
+            .expression_attribute_names("#status", "status")
+            .expression_attribute_values(":published", AttributeValue::S(PostStatus::Published.as_str().into()))
+            .set_exclusive_start_key(start_key)
+            .send()
+            .await?;
+        for item in page.items.unwrap_or_default() {
+            let Some(id) = item.get("id").and_then(|it| it.as_s().ok()) else {
+                continue;
+            };
+            let location = item.get("location").and_then(|it| it.as_s().ok()?.parse::<GeoPoint>().ok());
+            let Some(location) = location else {
+                tracing::warn!(id, "post has no valid location, skipping");
+                skipped += 1;
+                continue;
+            };
+            let mut request = client.client.update_item()
+                .table_name("SocialMediaPosts")
+                .key("id", AttributeValue::S(id.clone()))
+                .condition_expression("attribute_exists(id) AND attribute_not_exists(deleted_at)");
+            let mut assignments = Vec::new();
+            for (i, (name, value)) in geohash_attributes(location).into_iter().enumerate() {
+                assignments.push(format!("#gh{i} = :gh{i}"));
+                request = request
+                    .expression_attribute_names(format!("#gh{i}"), name)
+                    .expression_attribute_values(format!(":gh{i}"), value);
+            }
+            request
+                .update_expression(format!("SET {} REMOVE r_long, r_lat, #region", assignments.join(", ")))
+                .expression_attribute_names("#region", "region")
+                .send()
+                .await?;
+            updated += 1;
+        }
+        start_key = page.last_evaluated_key;
+        if start_key.is_none() {
+            break;
+        }
+    }
+    tracing::info!(updated, skipped, "geohash backfill finished");
+    Ok(())
+}
+
+/// Removes posts deleted more than [`TOMBSTONE_RETENTION`] ago together with their edit
+/// history and upload reservation, retrying the media deletion in case it failed at the time.
+async fn purge_deleted() -> Result<(), Error> {
+    let client = DynamoDBClient::new().await?;
+    let cutoff = (SystemTime::now() - TOMBSTONE_RETENTION).duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as u64;
+    let mut purged = 0;
+    let mut start_key: Option<HashMap<String, AttributeValue>> = None;
+    loop {
+        let page = client.client.scan()
+            .table_name("SocialMediaPosts")
+            .projection_expression("id, content_id, media")
+            .filter_expression("deleted_at < :cutoff")
+            .expression_attribute_values(":cutoff", AttributeValue::N(cutoff.to_string()))
+            .set_exclusive_start_key(start_key)
+            .send()
+            .await?;
+        for item in page.items.unwrap_or_default() {
+            let Some(id) = item.get("id").and_then(|it| it.as_s().ok()) else {
+                continue;
+            };
+            // Posts from before version 2 have no media list and use their id as content id.
+            let content_ids = match item.get("media").and_then(|it| it.as_l().ok()) {
+                Some(media) => media.iter()
+                    .filter_map(|it| it.as_m().ok()?.get("content_id")?.as_s().ok().cloned())
+                    .collect(),
+                None => vec![item.get("content_id").and_then(|it| it.as_s().ok()).unwrap_or(id).clone()],
+            };
+            for content_id in &content_ids {
+                delete_media(content_id).await?;
+            }
+            let history = client.client.query()
+                .table_name("SocialMediaPostHistory")
+                .key_condition_expression("post_id = :post_id")
+                .expression_attribute_values(":post_id", AttributeValue::S(id.clone()))
+                .projection_expression("post_id, replaced_at")
+                .into_paginator()
+                .items()
+                .send()
+                .collect::<Result<Vec<_>, _>>()
+                .await?;
+            for key in history {
+                client.client.delete_item().table_name("SocialMediaPostHistory").set_key(Some(key)).send().await?;
+            }
+            for content_id in content_ids {
+                client.client.delete_item()
+                    .table_name("SocialMediaUploads")
+                    .key("content_id", AttributeValue::S(content_id))
+                    .send()
+                    .await?;
+            }
+            // Deleted last, so a failed run finds the post again next time.
+            client.client.delete_item()
+                .table_name("SocialMediaPosts")
+                .key("id", AttributeValue::S(id.clone()))
+                .condition_expression("attribute_exists(deleted_at)")
+                .send()
+                .await?;
+            purged += 1;
+        }
+        start_key = page.last_evaluated_key;
+        if start_key.is_none() {
+            break;
+        }
+    }
+    tracing::info!(purged, "purge of deleted posts finished");
+    Ok(())
+}
+
+/// Publishes scheduled posts whose `publish_at` has passed and adds them to the geohash indexes.
+/// Meant to run every few minutes, so posts go live shortly after their time.
+async fn publish_scheduled() -> Result<(), Error> {
+    let client = DynamoDBClient::new().await?;
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as u64;
+    let (mut published, mut skipped) = (0, 0);
+    let mut start_key: Option<HashMap<String, AttributeValue>> = None;
+    loop {
+        let page = client.client.scan()
+            .table_name("SocialMediaPosts")
+            .projection_expression("id, #location")
+            .filter_expression("#status = :scheduled AND publish_at <= :now AND attribute_not_exists(deleted_at)")
+            .expression_attribute_names("#location", "location")
+            .expression_attribute_names("#status", "status")
+            .expression_attribute_values(":scheduled", AttributeValue::S(PostStatus::Scheduled.as_str().into()))
+            .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+            .set_exclusive_start_key(start_key)
+            .send()
+            .await?;
+        for item in page.items.unwrap_or_default() {
+            let Some(id) = item.get("id").and_then(|it| it.as_s().ok()) else {
+                continue;
+            };
+            let location = item.get("location").and_then(|it| it.as_s().ok()?.parse::<GeoPoint>().ok());
+            let Some(location) = location else {
+                tracing::warn!(id, "scheduled post has no valid location, skipping");
+                skipped += 1;
+                continue;
+            };
+            let mut request = client.client.update_item()
+                .table_name("SocialMediaPosts")
+                .key("id", AttributeValue::S(id.clone()))
+                // The author may have rescheduled or deleted the post since the scan.
+                .condition_expression("#status = :scheduled AND publish_at <= :now AND attribute_not_exists(deleted_at)")
+                .expression_attribute_names("#status", "status")
+                .expression_attribute_values(":scheduled", AttributeValue::S(PostStatus::Scheduled.as_str().into()))
+                .expression_attribute_values(":published", AttributeValue::S(PostStatus::Published.as_str().into()))
+                .expression_attribute_values(":now", AttributeValue::N(now.to_string()));
+            let mut assignments = vec!["#status = :published".to_string()];
+            for (i, (name, value)) in geohash_attributes(location).into_iter().enumerate() {
+                assignments.push(format!("#gh{i} = :gh{i}"));
+                request = request
+                    .expression_attribute_names(format!("#gh{i}"), name)
+                    .expression_attribute_values(format!(":gh{i}"), value);
+            }
+            let result = request
+                .update_expression(format!("SET {}", assignments.join(", ")))
+                .send()
+                .await;
+            match result {
+                Ok(_) => published += 1,
+                Err(e) => match e.into_service_error() {
+                    UpdateItemError::ConditionalCheckFailedException(_) => skipped += 1,
+                    e => return Err(e.into()),
+                },
+            }
+        }
+        start_key = page.last_evaluated_key;
+        if start_key.is_none() {
+            break;
+        }
+    }
+    tracing::info!(published, skipped, "publishing scheduled posts finished");
+    Ok(())
+}
+
+/// Gives posts stored before precision settings existed the [`LocationPrecision::LEGACY`]
+/// precision: keeps what they stored as `exact_location` and replaces `location`, `place`
+/// and, for published posts, the geohash attributes with the coarsened location.
+async fn coarsen_legacy_locations() -> Result<(), Error> {
+    let client = DynamoDBClient::new().await?;
+    let (mut updated, mut skipped) = (0, 0);
+    let mut start_key: Option<HashMap<String, AttributeValue>> = None;
+    loop {
+        let page = client.client.scan()
+            .table_name("SocialMediaPosts")
+            .projection_expression("id, #location, #status")
+            .filter_expression("attribute_not_exists(location_precision) AND attribute_not_exists(deleted_at)")
+            .expression_attribute_names("#location", "location")
+            .expression_attribute_names("#status", "status")
+            .set_exclusive_start_key(start_key)
+            .send()
+            .await?;
+        for item in page.items.unwrap_or_default() {
+            let Some(id) = item.get("id").and_then(|it| it.as_s().ok()) else {
+                continue;
+            };
+            let stored = item.get("location").and_then(|it| it.as_s().ok());
+            let Some((stored, exact)) = stored.and_then(|it| Some((it, it.parse::<GeoPoint>().ok()?))) else {
+                tracing::warn!(id, "post has no valid location, skipping");
+                skipped += 1;
+                continue;
+            };
+            let public_location = LocationPrecision::LEGACY.public_location(exact, id);
+            let mut request = client.client.update_item()
+                .table_name("SocialMediaPosts")
+                .key("id", AttributeValue::S(id.clone()))
+                // The author may have edited or deleted the post since the scan.
+                .condition_expression("attribute_not_exists(location_precision) AND #location = :exact AND attribute_not_exists(deleted_at)")
+                .expression_attribute_names("#location", "location")
+                .expression_attribute_values(":exact", AttributeValue::S(stored.clone()))
+                .expression_attribute_values(":public", AttributeValue::S(public_location.to_string()))
+                .expression_attribute_values(":precision", AttributeValue::S(LocationPrecision::LEGACY.as_str().into()));
+            let mut assignments = vec!["exact_location = :exact".to_string(), "#location = :public".into(), "location_precision = :precision".into()];
+            let mut removes = vec![];
+            match reverse_geocode(public_location) {
+                Some(place) => {
+                    assignments.push("place = :place".into());
+                    request = request.expression_attribute_values(":place", place_to_attribute(&place));
+                }
+                None => removes.push("place"),
+            }
+            // Only published posts are in the recommendation indexes.
+            let published = item.get("status").and_then(|it| it.as_s().ok()).is_none_or(|it| it == PostStatus::Published.as_str());
+            if published {
+                for (i, (name, value)) in geohash_attributes(public_location).into_iter().enumerate() {
+                    assignments.push(format!("#gh{i} = :gh{i}"));
+                    request = request
+                        .expression_attribute_names(format!("#gh{i}"), name)
+                        .expression_attribute_values(format!(":gh{i}"), value);
+                }
+            }
+            let mut update_expression = format!("SET {}", assignments.join(", "));
+            if !removes.is_empty() {
+                update_expression += &format!(" REMOVE {}", removes.join(", "));
+            }
+            match request.update_expression(update_expression).send().await {
+                Ok(_) => updated += 1,
+                Err(e) => match e.into_service_error() {
+                    UpdateItemError::ConditionalCheckFailedException(_) => skipped += 1,
+                    e => return Err(e.into()),
+                },
+            }
+        }
+        start_key = page.last_evaluated_key;
+        if start_key.is_none() {
+            break;
+        }
+    }
+    tracing::info!(updated, skipped, "coarsening legacy locations finished");
+    Ok(())
+}
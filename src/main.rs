@@ -1,15 +1,44 @@
-use lambda_http::{run, service_fn, tracing, Error};
+use lambda_http::{lambda_runtime::{self, LambdaEvent}, run, service_fn, tracing, Error};
 mod http_handler;
+mod api_error;
+mod auth;
+mod router;
 use http_handler::function_handler;
 mod media_upload;
 mod info_upload;
 mod post_download;
+mod post_edit;
+mod post_delete;
 mod recommendations;
 mod post_sorting;
+mod post_model;
+mod geo;
+mod gazetteer;
+mod upload_reservation;
+mod idempotency;
+mod server_key;
+mod nonce_store;
+mod request_signing;
+mod cert_revocation;
+mod session;
+mod rate_limit;
+mod cors;
+mod jobs;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     tracing::init_default_subscriber();
 
+    if let Some(job) = std::env::args().nth(1) {
+        return jobs::run(&job).await;
+    }
+    // Deployed a second time with `JOB` set, the function runs that job on every invocation,
+    // e.g. from an EventBridge schedule, instead of serving HTTP requests.
+    if let Ok(job) = std::env::var("JOB") {
+        let job = job.as_str();
+        return lambda_runtime::run(lambda_runtime::service_fn(|_: LambdaEvent<serde_json::Value>| jobs::run(job))).await;
+    }
+
+    gazetteer::load();
     run(service_fn(function_handler)).await
 }
@@ -2,10 +2,13 @@ use std::time::Duration;
 
 use aws_config::{load_defaults, BehaviorVersion};
 use aws_sdk_s3::{presigning::PresigningConfig, Client};
-use lambda_http::{Body, Error, Request, Response};
+use lambda_http::{Body, Request, Response};
 use uuid::Uuid;
 
-pub async fn media_upload(event: Request) -> Result<Response<Body>, Error> {
+use crate::{api_error::ApiError, auth::Caller, info_upload::DynamoDBClient, upload_reservation::reserve};
+
+#[allow(dead_code)]
+pub async fn media_upload(event: Request) -> Result<Response<Body>, ApiError> {
     let bytes: Vec<u8> = event.into_body().to_vec();
     let content_id = Uuid::new_v4().to_string();
     
@@ -32,8 +35,11 @@ pub async fn media_upload(event: Request) -> Result<Response<Body>, Error> {
     Ok(resp)
 }
 
-pub async fn media_upload_url(_event: Request) -> Result<Response<Body>, Error> {
+/// Hands out a presigned upload URL and reserves its content id for the caller.
+pub async fn media_upload_url(event: Request) -> Result<Response<Body>, ApiError> {
+    let username = Caller::of(&event).username().unwrap().to_string();
     let content_id = Uuid::new_v4().to_string();
+    reserve(&DynamoDBClient::new().await?, &content_id, &username).await?;
     
     // Create an S3 client
     let config = load_defaults(BehaviorVersion::latest()).await;
@@ -0,0 +1,159 @@
+use std::{collections::HashMap, sync::{Arc, Mutex, OnceLock}, time::{Duration, SystemTime}};
+
+use aws_sdk_dynamodb::{operation::put_item::PutItemError, types::AttributeValue};
+use lambda_http::Error;
+
+use crate::info_upload::DynamoDBClient;
+
+/// How far a nonce timestamp may be from the current time in either direction.
+pub const NONCE_WINDOW: Duration = Duration::from_secs(60);
+const NONCE_TABLE: &str = "SocialMediaNonces";
+const MIN_RANDOM_LEN: usize = 16;
+const MAX_RANDOM_LEN: usize = 64;
+
+type SeenNonces = Arc<Mutex<HashMap<(String, String), SystemTime>>>;
+
+/// Remembers which `(username, nonce)` pairs have already been used.
+pub enum NonceStore {
+    /// Conditional writes into the `SocialMediaNonces` table, which expires entries through its `expires` TTL attribute.
+    DynamoDB(DynamoDBClient),
+    /// Only remembers nonces inside this process, for tests and local runs.
+    Memory(SeenNonces),
+}
+
+impl NonceStore {
+    /// Uses the in-memory store when `NONCE_STORE=memory`, DynamoDB otherwise.
+    pub async fn from_env() -> Result<Self, Error> {
+        if std::env::var("NONCE_STORE").is_ok_and(|it| it == "memory") {
+            static MEMORY: OnceLock<SeenNonces> = OnceLock::new();
+            return Ok(Self::Memory(MEMORY.get_or_init(Default::default).clone()));
+        }
+        Ok(Self::DynamoDB(DynamoDBClient::new().await?))
+    }
+
+    /// Records a nonce as used.
+    /// Returns `false` if the same user already used it, in which case the request is a replay.
+    pub async fn record(&self, username: &str, nonce: &str, timestamp: Duration) -> Result<bool, Error> {
+        let expires = SystemTime::UNIX_EPOCH + timestamp + NONCE_WINDOW;
+        match self {
+            Self::DynamoDB(client) => {
+                let result = client.client.put_item()
+                    .table_name(NONCE_TABLE)
+                    .item("id", AttributeValue::S(format!("{username}#{nonce}")))
+                    .item("expires", AttributeValue::N(expires.duration_since(SystemTime::UNIX_EPOCH)?.as_secs().to_string()))
+                    .condition_expression("attribute_not_exists(id)")
+                    .send()
+                    .await;
+                match result {
+                    Ok(_) => Ok(true),
+                    Err(e) => match e.into_service_error() {
+                        PutItemError::ConditionalCheckFailedException(_) => Ok(false),
+                        e => Err(e.into()),
+                    },
+                }
+            }
+            Self::Memory(seen) => {
+                let now = SystemTime::now();
+                let mut seen = seen.lock().unwrap();
+                seen.retain(|_, expires| *expires > now);
+                if seen.contains_key(&(username.to_string(), nonce.to_string())) {
+                    return Ok(false);
+                }
+                seen.insert((username.to_string(), nonce.to_string()), expires);
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// Parses a nonce of the form `<unix millis>.<random>` and returns its timestamp.
+/// The random part must be 16 to 64 characters of `[A-Za-z0-9_-]` so two requests sent
+/// in the same millisecond still get distinct nonces.
+///
+/// With `allow_legacy`, a bare `<unix millis>` as sent by clients that predate the random
+/// part is accepted too. Callers allow it only for [`SignatureVersion::V1`] requests, so it
+/// goes away together with legacy signatures.
+///
+/// [`SignatureVersion::V1`]: crate::request_signing::SignatureVersion::V1
+pub fn parse_nonce(nonce: &str, allow_legacy: bool) -> Option<Duration> {
+    let Some((timestamp, random)) = nonce.split_once('.') else {
+        return allow_legacy.then(|| Some(Duration::from_millis(nonce.parse().ok()?))).flatten();
+    };
+    if !(MIN_RANDOM_LEN..=MAX_RANDOM_LEN).contains(&random.len())
+        || !random.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return None;
+    }
+    Some(Duration::from_millis(timestamp.parse().ok()?))
+}
+
+/// Checks that a nonce timestamp lies within [`NONCE_WINDOW`] of now.
+pub fn timestamp_in_window(timestamp: Duration) -> bool {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+    if timestamp > now {
+        timestamp - now <= NONCE_WINDOW
+    } else {
+        now - timestamp <= NONCE_WINDOW
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> Duration {
+        SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap()
+    }
+
+    #[test]
+    fn parses_timestamp_and_random_part() {
+        assert_eq!(parse_nonce("1700000000000.abcdefghijklmnop", false), Some(Duration::from_millis(1_700_000_000_000)));
+        assert_eq!(parse_nonce("1700000000000.A-b_c0123456789xyz", false), Some(Duration::from_millis(1_700_000_000_000)));
+    }
+
+    #[test]
+    fn rejects_bad_random_part() {
+        assert_eq!(parse_nonce("1700000000000.short", false), None);
+        assert_eq!(parse_nonce(&format!("1700000000000.{}", "a".repeat(65)), false), None);
+        assert_eq!(parse_nonce("1700000000000.abcdefghijklmn!p", false), None);
+        assert_eq!(parse_nonce("1700000000000.abcdefghijklmnöp", false), None);
+        assert_eq!(parse_nonce("not-a-number.abcdefghijklmnop", false), None);
+        assert_eq!(parse_nonce("-5.abcdefghijklmnop", false), None);
+    }
+
+    #[test]
+    fn legacy_nonce_only_when_allowed() {
+        assert_eq!(parse_nonce("1700000000000", false), None);
+        assert_eq!(parse_nonce("1700000000000", true), Some(Duration::from_millis(1_700_000_000_000)));
+        assert_eq!(parse_nonce("garbage", true), None);
+        assert_eq!(parse_nonce("", true), None);
+    }
+
+    #[test]
+    fn window_is_symmetric_around_now() {
+        assert!(timestamp_in_window(now()));
+        assert!(timestamp_in_window(now() - NONCE_WINDOW + Duration::from_secs(1)));
+        assert!(timestamp_in_window(now() + NONCE_WINDOW - Duration::from_secs(1)));
+        assert!(!timestamp_in_window(now() - NONCE_WINDOW - Duration::from_secs(1)));
+        assert!(!timestamp_in_window(now() + NONCE_WINDOW + Duration::from_secs(1)));
+        assert!(!timestamp_in_window(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn memory_store_rejects_replays() {
+        let store = NonceStore::Memory(Default::default());
+        let timestamp = now();
+        assert!(store.record("alice", "n1", timestamp).await.unwrap());
+        assert!(!store.record("alice", "n1", timestamp).await.unwrap());
+        // Nonces are per user.
+        assert!(store.record("bob", "n1", timestamp).await.unwrap());
+        assert!(store.record("alice", "n2", timestamp).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn memory_store_forgets_expired_nonces() {
+        let store = NonceStore::Memory(Default::default());
+        let expired = now() - NONCE_WINDOW - Duration::from_secs(1);
+        assert!(store.record("alice", "n1", expired).await.unwrap());
+        assert!(store.record("alice", "n1", expired).await.unwrap());
+    }
+}
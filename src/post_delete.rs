@@ -0,0 +1,98 @@
+use std::time::{Duration, SystemTime};
+
+use aws_config::{load_defaults, BehaviorVersion};
+use aws_sdk_dynamodb::{operation::update_item::UpdateItemError, types::{AttributeValue, ReturnValue}};
+use lambda_http::{tracing, Body, Error, Request, RequestExt, Response};
+
+use crate::{api_error::ApiError, auth::Caller, geo::{geohash_attribute, GEOHASH_PRECISIONS}, info_upload::DynamoDBClient, post_download::can_view, post_model::PostRecord};
+
+const MEDIA_BUCKET: &str = "social-media-post-media";
+/// How long a tombstone is kept before the purge job removes the post for good.
+pub const TOMBSTONE_RETENTION: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+
+/// `DELETE /posts/{id}`: soft-deletes the caller's post and removes all of its media.
+///
+/// The item stays as a tombstone with `deleted_at` set, which `can_view` hides, and loses its
+/// geohash attributes so it drops out of the recommendation indexes. The `purge-deleted` job
+/// removes it after [`TOMBSTONE_RETENTION`].
+pub async fn delete_post(event: Request) -> Result<Response<Body>, ApiError> {
+    let caller = Caller::of(&event);
+    let username = caller.username().unwrap().to_string();
+    let Some(post_id) = event.path_parameters().first("id").map(str::to_string) else {
+        return Err(ApiError::bad_request("missing_parameter", "post id is required"));
+    };
+    let client = DynamoDBClient::new().await?;
+    let item = client.get_item("SocialMediaPosts", [("id".into(), AttributeValue::S(post_id.clone()))].into()).await?;
+    let Some(item) = item.filter(|it| can_view(it, &caller)) else {
+        return Err(ApiError::not_found("post_not_found", "post not found"));
+    };
+    // Posts from before the typed schema only name their author inside `info`.
+    let Some(post) = PostRecord::from_item(&item) else {
+        return Err(ApiError::internal(format!("post {post_id} is malformed").into()));
+    };
+    if post.username != username {
+        return Err(ApiError::forbidden("not_post_owner", "only the author can delete a post"));
+    }
+
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+    let geohashes = GEOHASH_PRECISIONS.map(geohash_attribute).join(", ");
+    let result = client.client.update_item()
+        .table_name("SocialMediaPosts")
+        .key("id", AttributeValue::S(post_id.clone()))
+        .update_expression(format!("SET deleted_at = :now, username = :username REMOVE {geohashes}"))
+        .condition_expression("(attribute_not_exists(username) OR username = :username) AND attribute_not_exists(deleted_at)")
+        .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+        .expression_attribute_values(":username", AttributeValue::S(username))
+        .return_values(ReturnValue::AllOld)
+        .send()
+        .await;
+    let old = match result {
+        Ok(output) => output.attributes.unwrap_or_default(),
+        // Deleted by a concurrent request since it was read.
+        Err(e) => return match e.into_service_error() {
+            UpdateItemError::ConditionalCheckFailedException(_) => Err(ApiError::not_found("post_not_found", "post not found")),
+            e => Err(e.into()),
+        },
+    };
+
+    // The tombstone is already written, so a failure here is left for the purge job to retry.
+    for media in PostRecord::from_item(&old).map(|it| it.media).unwrap_or_default() {
+        if let Err(e) = delete_media(&media.content_id).await {
+            tracing::warn!(content_id = media.content_id, error = %e, "deleting media failed, leaving it to the purge job");
+        }
+    }
+
+    Ok(Response::builder()
+        .status(204)
+        .body(Body::Empty)
+        .unwrap())
+}
+
+/// Deletes an uploaded object and its derived variants, stored under `{content_id}/`.
+pub async fn delete_media(content_id: &str) -> Result<(), Error> {
+    let config = load_defaults(BehaviorVersion::latest()).await;
+    let client = aws_sdk_s3::Client::new(&config);
+    let mut keys = vec![content_id.to_string()];
+    let mut continuation_token = None;
+    loop {
+        let page = client.list_objects_v2()
+            .bucket(MEDIA_BUCKET)
+            .prefix(format!("{content_id}/"))
+            .set_continuation_token(continuation_token)
+            .send()
+            .await?;
+        keys.extend(page.contents.unwrap_or_default().into_iter().filter_map(|it| it.key));
+        continuation_token = page.next_continuation_token;
+        if continuation_token.is_none() {
+            break;
+        }
+    }
+    for key in keys {
+        client.delete_object()
+            .bucket(MEDIA_BUCKET)
+            .key(&key)
+            .send()
+            .await?;
+    }
+    Ok(())
+}
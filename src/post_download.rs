@@ -1,43 +1,84 @@
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
 use aws_config::{load_defaults, BehaviorVersion};
 use aws_sdk_dynamodb::types::AttributeValue;
 use aws_sdk_s3::{operation::get_object::GetObjectError, presigning::PresigningConfig};
-use lambda_http::{Body, Error, Request, RequestExt, Response};
+use lambda_http::{Body, Request, RequestExt, Response};
 
-use crate::info_upload::DynamoDBClient;
+use crate::{api_error::ApiError, auth::Caller, info_upload::DynamoDBClient, post_model::PostRecord};
 
-pub async fn get_info(event: Request) -> Result<Response<Body>, Error> {
+/// Whether `caller` may see a `SocialMediaPosts` item. Deleted posts are hidden from everyone;
+/// private, draft and scheduled posts from everyone but the author.
+/// Posts stored before visibility or status existed lack those attributes and are public.
+pub fn can_view(item: &HashMap<String, AttributeValue>, caller: &Caller) -> bool {
+    if item.contains_key("deleted_at") {
+        return false;
+    }
+    let public = item.get("visibility").and_then(|it| it.as_s().ok()).is_none_or(|it| it == "public");
+    let published = item.get("status").and_then(|it| it.as_s().ok()).is_none_or(|it| it == "published");
+    if public && published {
+        return true;
+    }
+    let owner = item.get("username").and_then(|it| it.as_s().ok());
+    caller.username().is_some_and(|username| owner.is_some_and(|owner| owner == username))
+}
+
+/// Looks up a post by its id, or by the content id of one of its media items.
+/// Returns `None` if it does not exist or `caller` may not see it.
+async fn find_post(client: &DynamoDBClient, id: &str, caller: &Caller) -> Result<Option<PostRecord>, ApiError> {
+    let mut item = client.get_item("SocialMediaPosts", [("id".into(), AttributeValue::S(id.into()))].into()).await?;
+    if item.is_none() {
+        let upload = client.get_item("SocialMediaUploads", [("content_id".into(), AttributeValue::S(id.into()))].into()).await?;
+        if let Some(post_id) = upload.as_ref().and_then(|it| it.get("post_id")?.as_s().ok()) {
+            item = client.get_item("SocialMediaPosts", [("id".into(), AttributeValue::S(post_id.clone()))].into()).await?;
+        }
+    }
+    let Some(item) = item.filter(|it| can_view(it, caller)) else {
+        return Ok(None);
+    };
+    match PostRecord::from_item(&item) {
+        Some(post) => Ok(Some(post)),
+        None => Err(ApiError::internal(format!("post {id} is malformed").into())),
+    }
+}
+
+/// Serves both `/get-info?content_id=` and `/posts/{id}`. `content_id` may also be a post id.
+pub async fn get_info(event: Request) -> Result<Response<Body>, ApiError> {
+    let path_params = event.path_parameters();
     let params = event.query_string_parameters();
-    let Some(content_id) = params.first("content_id") else {
-        return Ok(Response::builder()
-            .status(400)
-            .body(Body::from("400 - No content id"))
-            .unwrap());
+    let Some(id) = path_params.first("id").or(params.first("content_id")) else {
+        return Err(ApiError::bad_request("missing_parameter", "content_id is required"));
     };
     let client = DynamoDBClient::new().await?;
-    let Ok(Some(item)) = client.get_item("SocialMediaPosts", [("id".into(), AttributeValue::S(content_id.into()))].into()).await else {
-        return Ok(Response::builder()
-            .status(404)
-            .body(Body::from("404 - Post not found"))
-            .unwrap());
+    let caller = Caller::of(&event);
+    let Some(post) = find_post(&client, id, &caller).await? else {
+        return Err(ApiError::not_found("post_not_found", "post not found"));
     };
-    let info = item.get("info").unwrap().as_s().unwrap().clone();
+    let post = post.for_viewer(caller.username());
 
-    return Ok(Response::builder()
+    Ok(Response::builder()
         .status(200)
         .header("Content-Type", "application/json")
-        .body(Body::from(info))
-        .unwrap());
+        .body(Body::from(serde_json::to_string(&post)?))
+        .unwrap())
 }
 
-pub async fn get_media(event: Request) -> Result<Response<Body>, Error> {
+/// Whether `content_id` was reserved by `caller` and is not part of any post yet.
+async fn is_own_pending_upload(client: &DynamoDBClient, content_id: &str, caller: &Caller) -> Result<bool, ApiError> {
+    let Some(username) = caller.username() else {
+        return Ok(false);
+    };
+    let upload = client.get_item("SocialMediaUploads", [("content_id".into(), AttributeValue::S(content_id.into()))].into()).await?;
+    Ok(upload.is_some_and(|it| {
+        !it.contains_key("post_id") && it.get("username").and_then(|it| it.as_s().ok()).is_some_and(|it| it == username)
+    }))
+}
+
+#[allow(dead_code)]
+pub async fn get_media(event: Request) -> Result<Response<Body>, ApiError> {
     let params = event.query_string_parameters();
     let Some(content_id) = params.first("content_id") else {
-        return Ok(Response::builder()
-            .status(400)
-            .body(Body::from("400 - No content id"))
-            .unwrap());
+        return Err(ApiError::bad_request("missing_parameter", "content_id is required"));
     };
 
     let config = load_defaults(BehaviorVersion::latest()).await;
@@ -51,33 +92,58 @@ pub async fn get_media(event: Request) -> Result<Response<Body>, Error> {
         Err(e) => {
             match e.as_service_error() {
                 Some(GetObjectError::NoSuchKey(_)) => {
-                    return Ok(Response::builder()
-                        .status(400)
-                        .body(Body::from("404 - Post not found"))
-                        .unwrap());
+                    return Err(ApiError::not_found("media_not_found", "media not found"));
                 }
                 _ => {
-                    return Err(Box::new(e));
+                    return Err(e.into());
                 }
             }
         }
     };
     let content_bytes = object.body.collect().await?.into_bytes();
 
-    return Ok(Response::builder()
+    Ok(Response::builder()
         .status(200)
         .body(Body::from(content_bytes.as_ref()))
-        .unwrap());
+        .unwrap())
 }
 
-pub async fn get_media_url(event: Request) -> Result<Response<Body>, Error> {
+/// Serves both `/get-media?content_id=` and `/posts/{id}/media/{index}`, where `index` counts
+/// from 0 in the post's media order. Anonymous callers only get media attached to a public post.
+pub async fn get_media_url(event: Request) -> Result<Response<Body>, ApiError> {
+    let path_params = event.path_parameters();
     let params = event.query_string_parameters();
-    let Some(content_id) = params.first("content_id") else {
-        return Ok(Response::builder()
-            .status(400)
-            .body(Body::from("400 - No content id"))
-            .unwrap());
+    let caller = Caller::of(&event);
+    let client = DynamoDBClient::new().await?;
+    let content_id = match (path_params.first("id"), path_params.first("index")) {
+        (Some(post_id), Some(index)) => {
+            let Ok(index) = index.parse::<usize>() else {
+                return Err(ApiError::bad_request("invalid_index", "media index must be a number"));
+            };
+            let Some(post) = find_post(&client, post_id, &caller).await? else {
+                return Err(ApiError::not_found("post_not_found", "post not found"));
+            };
+            let Some(media) = post.media.into_iter().nth(index) else {
+                return Err(ApiError::not_found("media_not_found", "media not found"));
+            };
+            media.content_id
+        }
+        _ => {
+            let Some(content_id) = params.first("content_id") else {
+                return Err(ApiError::bad_request("missing_parameter", "content_id is required"));
+            };
+            let visible = match find_post(&client, content_id, &caller).await? {
+                Some(post) => post.media.iter().any(|it| it.content_id == content_id),
+                // Media not attached to a post yet can still be fetched by whoever uploaded it.
+                None => is_own_pending_upload(&client, content_id, &caller).await?,
+            };
+            if !visible {
+                return Err(ApiError::not_found("media_not_found", "media not found"));
+            }
+            content_id.to_string()
+        }
     };
+    let content_id = content_id.as_str();
 
     let config = load_defaults(BehaviorVersion::latest()).await;
     let client = aws_sdk_s3::Client::new(&config);
@@ -90,20 +156,17 @@ pub async fn get_media_url(event: Request) -> Result<Response<Body>, Error> {
         Err(e) => {
             match e.as_service_error() {
                 Some(GetObjectError::NoSuchKey(_)) => {
-                    return Ok(Response::builder()
-                        .status(400)
-                        .body(Body::from("404 - Post not found"))
-                        .unwrap());
+                    return Err(ApiError::not_found("media_not_found", "media not found"));
                 }
                 _ => {
-                    return Err(Box::new(e));
+                    return Err(e.into());
                 }
             }
         }
     };
 
-    return Ok(Response::builder()
+    Ok(Response::builder()
         .status(200)
         .body(Body::from(presigned_request.uri()))
-        .unwrap());
+        .unwrap())
 }
\ No newline at end of file
@@ -0,0 +1,183 @@
+use std::{collections::HashMap, time::SystemTime};
+
+use aws_sdk_dynamodb::{operation::transact_write_items::TransactWriteItemsError, types::{AttributeValue, Put, TransactWriteItem, Update}};
+use lambda_http::{Body, Request, RequestExt, Response};
+
+use crate::{api_error::ApiError, auth::Caller, gazetteer::reverse_geocode, geo::GeoPoint, info_upload::{geohash_attributes, DynamoDBClient}, post_download::can_view, post_model::{place_to_attribute, PostEdit, PostRecord, PostStatus}};
+
+const HISTORY_TABLE: &str = "SocialMediaPostHistory";
+
+/// `PATCH /posts/{id}`: changes the caption, tags, alt text or location of the caller's post,
+/// or publishes, reschedules or returns to draft a post that is not published yet.
+///
+/// The replaced version is kept in `SocialMediaPostHistory`, keyed by `post_id` and
+/// `replaced_at`, in the same transaction as the update. Edits are applied only if nobody
+/// else edited the post since it was read, otherwise they fail with 409.
+pub async fn edit_post(event: Request) -> Result<Response<Body>, ApiError> {
+    let caller = Caller::of(&event);
+    let username = caller.username().unwrap().to_string();
+    let Some(post_id) = event.path_parameters().first("id").map(str::to_string) else {
+        return Err(ApiError::bad_request("missing_parameter", "post id is required"));
+    };
+    let edit = match serde_json::from_slice::<PostEdit>(event.body()) {
+        Ok(edit) => edit,
+        Err(e) => return Err(ApiError::bad_request("invalid_body", e.to_string())),
+    };
+    edit.validate()?;
+    let location = match edit.location.as_deref().map(str::parse::<GeoPoint>) {
+        None => None,
+        Some(Ok(location)) => Some(location),
+        Some(Err(e)) => return Err(ApiError::bad_request("invalid_location", e.to_string())),
+    };
+
+    let client = DynamoDBClient::new().await?;
+    let key: HashMap<String, AttributeValue> = [("id".into(), AttributeValue::S(post_id.clone()))].into();
+    let Some(item) = client.get_item("SocialMediaPosts", key.clone()).await? else {
+        return Err(ApiError::not_found("post_not_found", "post not found"));
+    };
+    if !can_view(&item, &caller) {
+        return Err(ApiError::not_found("post_not_found", "post not found"));
+    }
+    let Some(previous) = PostRecord::from_item(&item) else {
+        return Err(ApiError::internal(format!("post {post_id} is malformed").into()));
+    };
+    if previous.username != username {
+        return Err(ApiError::forbidden("not_post_owner", "only the author can edit a post"));
+    }
+
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+    let mut post = previous.clone();
+    post.edited_at = Some(now);
+    let mut sets = vec!["#edited_at = :edited_at".to_string()];
+    let mut names = HashMap::from([("#edited_at".to_string(), "edited_at".to_string())]);
+    let mut values = HashMap::from([(":edited_at".to_string(), AttributeValue::N(now.to_string()))]);
+    let mut set = |name: String, value: AttributeValue| {
+        sets.push(format!("#{name} = :{name}"));
+        values.insert(format!(":{name}"), value);
+        names.insert(format!("#{name}"), name);
+    };
+    if let Some(caption) = edit.caption {
+        set("caption".into(), AttributeValue::S(caption.clone()));
+        post.caption = caption;
+    }
+    if let Some(tags) = edit.tags {
+        set("tags".into(), AttributeValue::L(tags.iter().cloned().map(AttributeValue::S).collect()));
+        post.tags = tags;
+    }
+    let mut removes = vec![];
+    let moved = location.is_some() || edit.location_precision.is_some();
+    if moved {
+        let exact = match location {
+            Some(location) => location,
+            None => match previous.exact_location().parse::<GeoPoint>() {
+                Ok(location) => location,
+                Err(_) => return Err(ApiError::internal(format!("post {post_id} has an invalid location").into())),
+            },
+        };
+        post.location_precision = edit.location_precision.unwrap_or(previous.location_precision);
+        let public_location = post.location_precision.public_location(exact, &post_id);
+        post.location = public_location.to_string();
+        post.exact_location = Some(exact.to_string());
+        post.place = reverse_geocode(public_location);
+        match &post.place {
+            Some(place) => set("place".into(), place_to_attribute(place)),
+            None => removes.push("place"),
+        }
+        set("location".into(), AttributeValue::S(post.location.clone()));
+        set("exact_location".into(), AttributeValue::S(exact.to_string()));
+        set("location_precision".into(), AttributeValue::S(post.location_precision.as_str().into()));
+    }
+    match edit.status {
+        Some(PostStatus::Published) if previous.status == PostStatus::Published => {}
+        Some(_) if previous.status == PostStatus::Published => {
+            return Err(ApiError::bad_request("already_published", "a published post can't become a draft or scheduled again"));
+        }
+        Some(status) => {
+            // Posts that were not published right away keep when they went live in `publish_at`.
+            post.publish_at = match status {
+                PostStatus::Published => Some(now),
+                _ => edit.publish_at,
+            };
+            post.status = status;
+            set("status".into(), AttributeValue::S(status.as_str().into()));
+            match post.publish_at {
+                Some(publish_at) => set("publish_at".into(), AttributeValue::N(publish_at.to_string())),
+                None => removes.push("publish_at"),
+            }
+        }
+        None => {}
+    }
+    // Only published posts are in the recommendation indexes.
+    let reindex = moved || post.status != previous.status;
+    if reindex && post.status == PostStatus::Published {
+        let Ok(location) = post.location.parse::<GeoPoint>() else {
+            return Err(ApiError::internal(format!("post {post_id} has an invalid location").into()));
+        };
+        for (name, value) in geohash_attributes(location) {
+            set(name, value);
+        }
+    }
+    if let Some(alt_texts) = edit.alt_texts {
+        if alt_texts.len() != post.media.len() {
+            return Err(ApiError::bad_request("invalid_alt_texts", format!("alt_texts must have one entry per media item ({})", post.media.len())));
+        }
+        for (media, alt_text) in post.media.iter_mut().zip(alt_texts) {
+            media.alt_text = Some(alt_text).filter(|it| !it.is_empty());
+        }
+        let media = post.to_item().remove("media").unwrap();
+        sets.push("#media = :media".into());
+        names.insert("#media".into(), "media".into());
+        values.insert(":media".into(), media);
+    }
+    // Posts from before the typed schema only name their author inside `info`, so the
+    // author is written out and only checked where it already is.
+    sets.push("#username = :username".into());
+    names.insert("#username".into(), "username".into());
+    values.insert(":username".into(), AttributeValue::S(username));
+    let condition = match previous.edited_at {
+        Some(edited_at) => {
+            values.insert(":previous_edited_at".into(), AttributeValue::N(edited_at.to_string()));
+            "(attribute_not_exists(#username) OR #username = :username) AND #edited_at = :previous_edited_at AND attribute_not_exists(deleted_at)"
+        }
+        None => "(attribute_not_exists(#username) OR #username = :username) AND attribute_not_exists(#edited_at) AND attribute_not_exists(deleted_at)",
+    };
+    let mut update_expression = format!("SET {}", sets.join(", "));
+    if !removes.is_empty() {
+        update_expression += &format!(" REMOVE {}", removes.join(", "));
+    }
+
+    let mut history = previous.to_item();
+    history.remove("id");
+    history.insert("post_id".into(), AttributeValue::S(post_id.clone()));
+    history.insert("replaced_at".into(), AttributeValue::N(now.to_string()));
+    let result = client.client.transact_write_items()
+        .transact_items(TransactWriteItem::builder()
+            .put(Put::builder().table_name(HISTORY_TABLE).set_item(Some(history)).build()?)
+            .build())
+        .transact_items(TransactWriteItem::builder()
+            .update(Update::builder()
+                .table_name("SocialMediaPosts")
+                .set_key(Some(key))
+                .update_expression(update_expression)
+                .condition_expression(condition)
+                .set_expression_attribute_names(Some(names))
+                .set_expression_attribute_values(Some(values))
+                .build()?)
+            .build())
+        .send()
+        .await;
+    if let Err(e) = result {
+        return match e.into_service_error() {
+            TransactWriteItemsError::TransactionCanceledException(_) => {
+                Err(ApiError::new(409, "edit_conflict", "the post changed while it was being edited, try again"))
+            }
+            e => Err(e.into()),
+        };
+    }
+
+    Ok(Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(&post)?))
+        .unwrap())
+}
@@ -0,0 +1,716 @@
+use std::{collections::HashMap, time::{Duration, SystemTime}};
+
+use aws_sdk_dynamodb::types::AttributeValue;
+
+use crate::{api_error::ApiError, gazetteer::{reverse_geocode, Place}, geo::GeoPoint};
+
+/// Version of the post schema below. Bump it when fields change meaning.
+/// Version 2 gave posts their own id and a list of media.
+pub const POST_SCHEMA_VERSION: u32 = 2;
+const MAX_MEDIA_ITEMS: usize = 10;
+/// How far ahead a post can be scheduled.
+const MAX_SCHEDULE_AHEAD: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+const MAX_CAPTION_CHARS: usize = 2200;
+const MAX_ALT_TEXT_CHARS: usize = 1000;
+const MAX_TAGS: usize = 30;
+const MAX_TAG_CHARS: usize = 50;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Readable by anyone, including callers without credentials.
+    #[default]
+    Public,
+    /// Only readable by the author.
+    Private,
+}
+
+impl Visibility {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Public => "public",
+            Self::Private => "private",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "public" => Some(Self::Public),
+            "private" => Some(Self::Private),
+            _ => None,
+        }
+    }
+}
+
+/// How precisely a post's location is shown to other users.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LocationPrecision {
+    Exact,
+    /// Somewhere within about 1 km.
+    #[default]
+    Neighborhood,
+    /// Somewhere within about 10 km.
+    City,
+}
+
+impl LocationPrecision {
+    /// Applied to posts stored before precision settings existed.
+    pub const LEGACY: Self = Self::Neighborhood;
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Exact => "exact",
+            Self::Neighborhood => "neighborhood",
+            Self::City => "city",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "exact" => Some(Self::Exact),
+            "neighborhood" => Some(Self::Neighborhood),
+            "city" => Some(Self::City),
+            _ => None,
+        }
+    }
+
+    /// The location other users see for a post at `exact`. Seeded by the post id, so it
+    /// stays the same across edits that don't move the post.
+    pub fn public_location(self, exact: GeoPoint, post_id: &str) -> GeoPoint {
+        match self {
+            Self::Exact => exact,
+            Self::Neighborhood => exact.obfuscate(0.01, post_id.as_bytes()),
+            Self::City => exact.obfuscate(0.1, post_id.as_bytes()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PostStatus {
+    /// Live, and listed by `recommend_posts`.
+    #[default]
+    Published,
+    /// Only visible to the author until they publish it.
+    Draft,
+    /// Only visible to the author until `publish_at`, when the `publish-scheduled` job publishes it.
+    Scheduled,
+}
+
+impl PostStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Published => "published",
+            Self::Draft => "draft",
+            Self::Scheduled => "scheduled",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "published" => Some(Self::Published),
+            "draft" => Some(Self::Draft),
+            "scheduled" => Some(Self::Scheduled),
+            _ => None,
+        }
+    }
+}
+
+/// Checks that `publish_at` (unix millis) is given exactly for scheduled posts and is in the future.
+fn validate_schedule(status: PostStatus, publish_at: Option<u64>) -> Result<(), ApiError> {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+    match (status, publish_at) {
+        (PostStatus::Scheduled, None) => {
+            Err(ApiError::bad_request("missing_publish_at", "scheduled posts need a publish_at time"))
+        }
+        (PostStatus::Scheduled, Some(publish_at)) => {
+            let publish_at = Duration::from_millis(publish_at);
+            if publish_at <= now || publish_at > now + MAX_SCHEDULE_AHEAD {
+                return Err(ApiError::bad_request("invalid_publish_at", "publish_at must be in the future and at most a year ahead"));
+            }
+            Ok(())
+        }
+        (_, Some(_)) => Err(ApiError::bad_request("invalid_publish_at", "publish_at is only allowed for scheduled posts")),
+        (_, None) => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaType {
+    #[default]
+    Image,
+    Video,
+}
+
+impl MediaType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Image => "image",
+            Self::Video => "video",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "image" => Some(Self::Image),
+            "video" => Some(Self::Video),
+            _ => None,
+        }
+    }
+}
+
+fn default_version() -> u32 {
+    POST_SCHEMA_VERSION
+}
+
+/// One photo or video of a post, in the order they are shown.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MediaItem {
+    pub content_id: String,
+    #[serde(default)]
+    pub media_type: MediaType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alt_text: Option<String>,
+}
+
+impl MediaItem {
+    fn to_attribute(&self) -> AttributeValue {
+        let mut item = HashMap::new();
+        item.insert("content_id".into(), AttributeValue::S(self.content_id.clone()));
+        item.insert("media_type".into(), AttributeValue::S(self.media_type.as_str().into()));
+        if let Some(alt_text) = &self.alt_text {
+            item.insert("alt_text".into(), AttributeValue::S(alt_text.clone()));
+        }
+        AttributeValue::M(item)
+    }
+
+    fn from_attribute(value: &AttributeValue) -> Option<Self> {
+        let item = value.as_m().ok()?;
+        Some(Self {
+            content_id: attr_s(item, "content_id")?.clone(),
+            media_type: attr_s(item, "media_type").and_then(|it| MediaType::parse(it)).unwrap_or_default(),
+            alt_text: attr_s(item, "alt_text").cloned(),
+        })
+    }
+}
+
+/// Body of `/post-info`. Unknown fields are rejected so nothing unvalidated gets stored.
+///
+/// Media is either listed in `media`, or given as a single `content_id` with `media_type`
+/// and `alt_text` like version 1 clients do.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewPost {
+    #[serde(default = "default_version")]
+    pub version: u32,
+    #[serde(default)]
+    pub media: Vec<MediaItem>,
+    #[serde(default)]
+    pub content_id: Option<String>,
+    /// `"longitude,latitude"`
+    pub location: String,
+    pub username: String,
+    #[serde(default)]
+    pub caption: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub alt_text: Option<String>,
+    #[serde(default)]
+    pub media_type: Option<MediaType>,
+    #[serde(default)]
+    pub visibility: Visibility,
+    #[serde(default)]
+    pub status: PostStatus,
+    /// Unix millis at which a `scheduled` post goes live.
+    #[serde(default)]
+    pub publish_at: Option<u64>,
+    #[serde(default)]
+    pub location_precision: LocationPrecision,
+}
+
+impl NewPost {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        if !(1..=POST_SCHEMA_VERSION).contains(&self.version) {
+            return Err(ApiError::bad_request("unsupported_version", format!("post version must be between 1 and {POST_SCHEMA_VERSION}")));
+        }
+        let single = self.content_id.is_some() || self.alt_text.is_some() || self.media_type.is_some();
+        let listed = !self.media.is_empty();
+        if single == listed {
+            return Err(ApiError::bad_request("invalid_media", "either media or content_id is required, not both"));
+        }
+        let media = self.media_items();
+        if media.len() > MAX_MEDIA_ITEMS {
+            return Err(ApiError::bad_request("too_many_media", format!("a post can have at most {MAX_MEDIA_ITEMS} media items")));
+        }
+        for (i, item) in media.iter().enumerate() {
+            if uuid::Uuid::parse_str(&item.content_id).is_err() {
+                return Err(ApiError::bad_request("invalid_content_id", "content_id must be a UUID"));
+            }
+            if media[..i].iter().any(|it| it.content_id == item.content_id) {
+                return Err(ApiError::bad_request("duplicate_media", format!("media {} is listed twice", item.content_id)));
+            }
+            if let Some(alt_text) = &item.alt_text {
+                validate_alt_text(alt_text)?;
+            }
+        }
+        validate_caption(&self.caption)?;
+        validate_tags(&self.tags)?;
+        validate_schedule(self.status, self.publish_at)
+    }
+
+    /// The post's media, whichever way it was given.
+    pub fn media_items(&self) -> Vec<MediaItem> {
+        match &self.content_id {
+            Some(content_id) => vec![MediaItem {
+                content_id: content_id.clone(),
+                media_type: self.media_type.unwrap_or_default(),
+                alt_text: self.alt_text.clone(),
+            }],
+            None => self.media.clone(),
+        }
+    }
+}
+
+fn validate_caption(caption: &str) -> Result<(), ApiError> {
+    if caption.chars().count() > MAX_CAPTION_CHARS {
+        return Err(ApiError::bad_request("caption_too_long", format!("caption must be at most {MAX_CAPTION_CHARS} characters")));
+    }
+    Ok(())
+}
+
+fn validate_alt_text(alt_text: &str) -> Result<(), ApiError> {
+    if alt_text.chars().count() > MAX_ALT_TEXT_CHARS {
+        return Err(ApiError::bad_request("alt_text_too_long", format!("alt_text must be at most {MAX_ALT_TEXT_CHARS} characters")));
+    }
+    Ok(())
+}
+
+fn validate_tags(tags: &[String]) -> Result<(), ApiError> {
+    if tags.len() > MAX_TAGS {
+        return Err(ApiError::bad_request("too_many_tags", format!("at most {MAX_TAGS} tags are allowed")));
+    }
+    let valid_tag = |tag: &String| (1..=MAX_TAG_CHARS).contains(&tag.chars().count())
+        && tag.chars().all(|c| c.is_alphanumeric() || c == '_');
+    if !tags.iter().all(valid_tag) {
+        return Err(ApiError::bad_request("invalid_tag", format!("tags must be 1 to {MAX_TAG_CHARS} letters, digits or underscores")));
+    }
+    Ok(())
+}
+
+/// Body of `PATCH /posts/{id}`. Only fields that are present change.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PostEdit {
+    pub caption: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// One per media item, in order; an empty string removes that item's alt text.
+    pub alt_texts: Option<Vec<String>>,
+    /// `"longitude,latitude"`
+    pub location: Option<String>,
+    /// Publishes or reschedules a draft or scheduled post.
+    pub status: Option<PostStatus>,
+    pub publish_at: Option<u64>,
+    pub location_precision: Option<LocationPrecision>,
+}
+
+impl PostEdit {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        let changes = [self.caption.is_some(), self.tags.is_some(), self.alt_texts.is_some(), self.location.is_some(), self.status.is_some(), self.location_precision.is_some()];
+        if !changes.contains(&true) {
+            return Err(ApiError::bad_request("empty_edit", "at least one of caption, tags, alt_texts, location, status or location_precision is required"));
+        }
+        match self.status {
+            Some(status) => validate_schedule(status, self.publish_at)?,
+            None if self.publish_at.is_some() => {
+                return Err(ApiError::bad_request("invalid_publish_at", "publish_at needs status scheduled"));
+            }
+            None => {}
+        }
+        if let Some(caption) = &self.caption {
+            validate_caption(caption)?;
+        }
+        for alt_text in self.alt_texts.iter().flatten() {
+            validate_alt_text(alt_text)?;
+        }
+        if let Some(tags) = &self.tags {
+            validate_tags(tags)?;
+        }
+        Ok(())
+    }
+}
+
+/// A post as stored in `SocialMediaPosts` and served by `get_info`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PostRecord {
+    pub version: u32,
+    pub id: String,
+    /// The first media item, for clients that only show one.
+    pub content_id: String,
+    pub media: Vec<MediaItem>,
+    pub username: String,
+    /// `"longitude,latitude"` as shown to everyone, coarsened according to `location_precision`.
+    pub location: String,
+    /// What the author sent. Only filled in for the author; never serve it to anyone else.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exact_location: Option<String>,
+    pub location_precision: LocationPrecision,
+    /// The city nearest to `location`, if any is close.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub place: Option<Place>,
+    pub caption: String,
+    pub tags: Vec<String>,
+    pub visibility: Visibility,
+    pub status: PostStatus,
+    /// Unix millis at which a scheduled post goes live, or went live for posts that were
+    /// not published when they were created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub publish_at: Option<u64>,
+    /// Unix millis.
+    pub created_at: u64,
+    /// Unix millis of the last edit, if the post was ever edited.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<u64>,
+}
+
+pub fn place_to_attribute(place: &Place) -> AttributeValue {
+    AttributeValue::M(HashMap::from([
+        ("name".into(), AttributeValue::S(place.name.clone())),
+        ("region".into(), AttributeValue::S(place.region.clone())),
+        ("country".into(), AttributeValue::S(place.country.clone())),
+    ]))
+}
+
+pub fn place_from_attribute(value: &AttributeValue) -> Option<Place> {
+    let item = value.as_m().ok()?;
+    Some(Place {
+        name: attr_s(item, "name")?.clone(),
+        region: attr_s(item, "region")?.clone(),
+        country: attr_s(item, "country")?.clone(),
+    })
+}
+
+fn attr_s<'a>(item: &'a HashMap<String, AttributeValue>, name: &str) -> Option<&'a String> {
+    item.get(name)?.as_s().ok()
+}
+
+impl PostRecord {
+    /// Gives the post a new random id. `post` must have passed [`NewPost::validate`].
+    pub fn new(post: NewPost, location: GeoPoint, created_at: u64) -> Self {
+        let media = post.media_items();
+        let id = uuid::Uuid::new_v4().to_string();
+        let public_location = post.location_precision.public_location(location, &id);
+        Self {
+            version: POST_SCHEMA_VERSION,
+            content_id: media[0].content_id.clone(),
+            media,
+            username: post.username,
+            location: public_location.to_string(),
+            // Named after the public location, so the name can't reveal more than it.
+            place: reverse_geocode(public_location),
+            exact_location: Some(location.to_string()),
+            location_precision: post.location_precision,
+            id,
+            caption: post.caption,
+            tags: post.tags,
+            visibility: post.visibility,
+            status: post.status,
+            publish_at: post.publish_at,
+            created_at,
+            edited_at: None,
+        }
+    }
+
+    /// Attributes of the post itself; index attributes such as the region are added by the caller.
+    pub fn to_item(&self) -> HashMap<String, AttributeValue> {
+        let mut item = HashMap::new();
+        item.insert("id".into(), AttributeValue::S(self.id.clone()));
+        item.insert("version".into(), AttributeValue::N(self.version.to_string()));
+        item.insert("content_id".into(), AttributeValue::S(self.content_id.clone()));
+        item.insert("media".into(), AttributeValue::L(self.media.iter().map(MediaItem::to_attribute).collect()));
+        item.insert("username".into(), AttributeValue::S(self.username.clone()));
+        item.insert("location".into(), AttributeValue::S(self.location.clone()));
+        if let Some(exact_location) = &self.exact_location {
+            item.insert("exact_location".into(), AttributeValue::S(exact_location.clone()));
+        }
+        item.insert("location_precision".into(), AttributeValue::S(self.location_precision.as_str().into()));
+        if let Some(place) = &self.place {
+            item.insert("place".into(), place_to_attribute(place));
+        }
+        item.insert("caption".into(), AttributeValue::S(self.caption.clone()));
+        item.insert("tags".into(), AttributeValue::L(self.tags.iter().cloned().map(AttributeValue::S).collect()));
+        item.insert("visibility".into(), AttributeValue::S(self.visibility.as_str().into()));
+        item.insert("status".into(), AttributeValue::S(self.status.as_str().into()));
+        if let Some(publish_at) = self.publish_at {
+            item.insert("publish_at".into(), AttributeValue::N(publish_at.to_string()));
+        }
+        item.insert("date".into(), AttributeValue::N(self.created_at.to_string()));
+        if let Some(edited_at) = self.edited_at {
+            item.insert("edited_at".into(), AttributeValue::N(edited_at.to_string()));
+        }
+        item
+    }
+
+    /// Hides the exact location unless `username` is the author.
+    pub fn for_viewer(mut self, username: Option<&str>) -> Self {
+        if username != Some(self.username.as_str()) {
+            self.exact_location = None;
+        }
+        self
+    }
+
+    /// The location the author sent, for posts stored before it was kept separately too.
+    pub fn exact_location(&self) -> &str {
+        self.exact_location.as_deref().unwrap_or(&self.location)
+    }
+
+    /// Reads a stored post. Posts written before the typed schema only have their original
+    /// request body in the `info` attribute, which is used to fill in the author. Posts written
+    /// before version 2 use their content id as post id and describe their single media item
+    /// in top-level attributes.
+    pub fn from_item(item: &HashMap<String, AttributeValue>) -> Option<Self> {
+        let id = attr_s(item, "id")?.clone();
+        let legacy_info = attr_s(item, "info").and_then(|it| serde_json::from_str::<serde_json::Value>(it).ok());
+        let username = match attr_s(item, "username") {
+            Some(username) => username.clone(),
+            None => legacy_info.as_ref()?.get("username")?.as_str()?.to_string(),
+        };
+        let content_id = attr_s(item, "content_id").unwrap_or(&id).clone();
+        // Posts from before precision settings stored only the exact location, which other
+        // users now get coarsened the way new posts are until `coarsen-legacy-locations` rewrites them.
+        let stored_location = attr_s(item, "location")?.clone();
+        let (location, exact_location, location_precision) = match attr_s(item, "location_precision") {
+            Some(precision) => (stored_location, attr_s(item, "exact_location").cloned(), LocationPrecision::parse(precision).unwrap_or_default()),
+            None => match stored_location.parse::<GeoPoint>() {
+                Ok(exact) => (LocationPrecision::LEGACY.public_location(exact, &id).to_string(), Some(stored_location), LocationPrecision::LEGACY),
+                Err(_) => (stored_location, None, LocationPrecision::LEGACY),
+            },
+        };
+        let media = match item.get("media").and_then(|it| it.as_l().ok()) {
+            Some(media) => media.iter().map(MediaItem::from_attribute).collect::<Option<Vec<_>>>()?,
+            None => vec![MediaItem {
+                content_id: content_id.clone(),
+                media_type: attr_s(item, "media_type").and_then(|it| MediaType::parse(it)).unwrap_or_default(),
+                alt_text: attr_s(item, "alt_text").cloned(),
+            }],
+        };
+        Some(Self {
+            version: item.get("version").and_then(|it| it.as_n().ok()?.parse().ok()).unwrap_or(1),
+            content_id,
+            media,
+            id,
+            username,
+            location,
+            exact_location,
+            location_precision,
+            place: item.get("place").and_then(place_from_attribute),
+            caption: attr_s(item, "caption").cloned().unwrap_or_default(),
+            tags: item.get("tags")
+                .and_then(|it| it.as_l().ok())
+                .map(|tags| tags.iter().filter_map(|it| it.as_s().ok().cloned()).collect())
+                .unwrap_or_default(),
+            visibility: attr_s(item, "visibility").and_then(|it| Visibility::parse(it)).unwrap_or_default(),
+            status: attr_s(item, "status").and_then(|it| PostStatus::parse(it)).unwrap_or_default(),
+            publish_at: item.get("publish_at").and_then(|it| it.as_n().ok()?.parse().ok()),
+            created_at: item.get("date")?.as_n().ok()?.parse().ok()?,
+            edited_at: item.get("edited_at").and_then(|it| it.as_n().ok()?.parse().ok()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const FIRST: &str = "5f0c6a52-3b1e-4a53-9d9e-1f2b7c1d2e3f";
+    const SECOND: &str = "0b7e1f9c-8f35-4c3a-a7d2-6c4e2b9d8a11";
+
+    fn now_millis() -> u64 {
+        SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64
+    }
+
+    /// Parses and validates a `/post-info` body made of a valid post with `fields` merged in,
+    /// returning the error code. `null` fields are removed.
+    fn new_post(fields: Value) -> Result<NewPost, &'static str> {
+        let mut body = json!({"location": "13.4,52.5", "username": "alice", "media": [{"content_id": FIRST}]});
+        for (name, value) in fields.as_object().unwrap() {
+            match value {
+                Value::Null => body.as_object_mut().unwrap().remove(name),
+                value => body.as_object_mut().unwrap().insert(name.clone(), value.clone()),
+            };
+        }
+        let post = serde_json::from_value::<NewPost>(body).map_err(|_| "invalid_body")?;
+        post.validate().map_err(|e| e.code)?;
+        Ok(post)
+    }
+
+    fn edit(body: Value) -> Result<PostEdit, &'static str> {
+        let edit = serde_json::from_value::<PostEdit>(body).map_err(|_| "invalid_body")?;
+        edit.validate().map_err(|e| e.code)?;
+        Ok(edit)
+    }
+
+    #[test]
+    fn accepts_valid_posts() {
+        let post = new_post(json!({"caption": "hi", "tags": ["sunset", "été_2024"]})).unwrap();
+        assert_eq!(post.version, POST_SCHEMA_VERSION);
+        let post = new_post(json!({"media": null, "content_id": FIRST, "media_type": "video", "alt_text": "a dog", "version": 1})).unwrap();
+        assert_eq!(post.media_items(), [MediaItem { content_id: FIRST.into(), media_type: MediaType::Video, alt_text: Some("a dog".into()) }]);
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert_eq!(new_post(json!({"likes": 1000})).unwrap_err(), "invalid_body");
+        assert_eq!(new_post(json!({"media": [{"content_id": FIRST, "url": "https://example.com"}]})).unwrap_err(), "invalid_body");
+        assert_eq!(edit(json!({"caption": "hi", "username": "mallory"})).unwrap_err(), "invalid_body");
+    }
+
+    #[test]
+    fn checks_version_range() {
+        assert_eq!(new_post(json!({"version": 0})).unwrap_err(), "unsupported_version");
+        assert_eq!(new_post(json!({"version": POST_SCHEMA_VERSION + 1})).unwrap_err(), "unsupported_version");
+        assert!(new_post(json!({"version": 1})).is_ok());
+        assert!(new_post(json!({"version": POST_SCHEMA_VERSION})).is_ok());
+    }
+
+    #[test]
+    fn media_and_content_id_are_exclusive() {
+        assert_eq!(new_post(json!({"content_id": SECOND})).unwrap_err(), "invalid_media");
+        assert_eq!(new_post(json!({"alt_text": "a dog"})).unwrap_err(), "invalid_media");
+        assert_eq!(new_post(json!({"media_type": "image"})).unwrap_err(), "invalid_media");
+        assert_eq!(new_post(json!({"media": null})).unwrap_err(), "invalid_media");
+        assert_eq!(new_post(json!({"media": []})).unwrap_err(), "invalid_media");
+    }
+
+    #[test]
+    fn checks_media_items() {
+        let media = json!([{"content_id": FIRST}, {"content_id": SECOND}, {"content_id": FIRST}]);
+        assert_eq!(new_post(json!({"media": media})).unwrap_err(), "duplicate_media");
+        assert_eq!(new_post(json!({"media": [{"content_id": "not-a-uuid"}]})).unwrap_err(), "invalid_content_id");
+        let too_many = (0..=MAX_MEDIA_ITEMS).map(|_| json!({"content_id": uuid::Uuid::new_v4().to_string()})).collect::<Vec<_>>();
+        assert_eq!(new_post(json!({"media": too_many})).unwrap_err(), "too_many_media");
+        assert!(new_post(json!({"media": too_many[1..]})).is_ok());
+    }
+
+    #[test]
+    fn checks_text_limits() {
+        assert!(new_post(json!({"caption": "é".repeat(MAX_CAPTION_CHARS)})).is_ok());
+        assert_eq!(new_post(json!({"caption": "a".repeat(MAX_CAPTION_CHARS + 1)})).unwrap_err(), "caption_too_long");
+        let alt_text = |len: usize| json!([{"content_id": FIRST, "alt_text": "é".repeat(len)}]);
+        assert!(new_post(json!({"media": alt_text(MAX_ALT_TEXT_CHARS)})).is_ok());
+        assert_eq!(new_post(json!({"media": alt_text(MAX_ALT_TEXT_CHARS + 1)})).unwrap_err(), "alt_text_too_long");
+        assert_eq!(edit(json!({"caption": "a".repeat(MAX_CAPTION_CHARS + 1)})).unwrap_err(), "caption_too_long");
+        assert_eq!(edit(json!({"alt_texts": ["", "a".repeat(MAX_ALT_TEXT_CHARS + 1)]})).unwrap_err(), "alt_text_too_long");
+    }
+
+    #[test]
+    fn checks_tags() {
+        let tags = |count: usize| (0..count).map(|i| format!("tag{i}")).collect::<Vec<_>>();
+        assert!(new_post(json!({"tags": tags(MAX_TAGS)})).is_ok());
+        assert_eq!(new_post(json!({"tags": tags(MAX_TAGS + 1)})).unwrap_err(), "too_many_tags");
+        assert!(new_post(json!({"tags": ["ü".repeat(MAX_TAG_CHARS)]})).is_ok());
+        assert_eq!(new_post(json!({"tags": ["a".repeat(MAX_TAG_CHARS + 1)]})).unwrap_err(), "invalid_tag");
+        for tag in ["", "#sunset", "two words", "semi;colon", "new\nline", "emoji😀"] {
+            assert_eq!(new_post(json!({"tags": [tag]})).unwrap_err(), "invalid_tag", "{tag:?}");
+            assert_eq!(edit(json!({"tags": [tag]})).unwrap_err(), "invalid_tag", "{tag:?}");
+        }
+    }
+
+    #[test]
+    fn checks_schedule() {
+        let hour = 60 * 60 * 1000;
+        assert!(validate_schedule(PostStatus::Scheduled, Some(now_millis() + hour)).is_ok());
+        assert_eq!(validate_schedule(PostStatus::Scheduled, None).unwrap_err().code, "missing_publish_at");
+        assert_eq!(validate_schedule(PostStatus::Scheduled, Some(now_millis() - hour)).unwrap_err().code, "invalid_publish_at");
+        let too_far = now_millis() + MAX_SCHEDULE_AHEAD.as_millis() as u64 + hour;
+        assert_eq!(validate_schedule(PostStatus::Scheduled, Some(too_far)).unwrap_err().code, "invalid_publish_at");
+        for status in [PostStatus::Published, PostStatus::Draft] {
+            assert!(validate_schedule(status, None).is_ok());
+            assert_eq!(validate_schedule(status, Some(now_millis() + hour)).unwrap_err().code, "invalid_publish_at");
+        }
+        assert_eq!(new_post(json!({"status": "scheduled"})).unwrap_err(), "missing_publish_at");
+        assert!(new_post(json!({"status": "scheduled", "publish_at": now_millis() + hour})).is_ok());
+    }
+
+    #[test]
+    fn edits_need_a_change() {
+        assert_eq!(edit(json!({})).unwrap_err(), "empty_edit");
+        assert_eq!(edit(json!({"publish_at": now_millis() + 60_000})).unwrap_err(), "empty_edit");
+        assert_eq!(edit(json!({"caption": "hi", "publish_at": now_millis() + 60_000})).unwrap_err(), "invalid_publish_at");
+        assert!(edit(json!({"status": "scheduled", "publish_at": now_millis() + 60_000})).is_ok());
+        assert!(edit(json!({"location_precision": "city"})).is_ok());
+    }
+
+    const COARSENED: [(LocationPrecision, f64); 2] = [(LocationPrecision::Neighborhood, 0.01), (LocationPrecision::City, 0.1)];
+
+    /// Whether `public` is within one `cell` of `exact` on both axes, across the antimeridian
+    /// and allowing for rounding to five decimals.
+    fn within_cell(exact: GeoPoint, public: GeoPoint, cell: f64) -> bool {
+        let longitude = (public.longitude() - exact.longitude()).abs();
+        let longitude = longitude.min(360. - longitude);
+        let latitude = (public.latitude() - exact.latitude()).abs();
+        longitude <= cell + 1e-5 && latitude <= cell + 1e-5
+    }
+
+    #[test]
+    fn exact_location_is_not_coarsened() {
+        let exact = GeoPoint::new(13.404954, 52.520008).unwrap();
+        assert_eq!(LocationPrecision::Exact.public_location(exact, FIRST), exact);
+    }
+
+    #[test]
+    fn public_location_differs_across_posts() {
+        let exact = GeoPoint::new(13.404954, 52.520008).unwrap();
+        for (precision, _) in COARSENED {
+            let locations = (0..100)
+                .map(|i| precision.public_location(exact, &format!("post-{i}")).to_string())
+                .collect::<std::collections::HashSet<_>>();
+            assert_eq!(locations.len(), 100, "{precision:?}");
+        }
+    }
+
+    #[test]
+    fn public_location_is_valid_at_the_edges() {
+        let edges = [
+            (-180., 0.), (179.999999, 0.), (179.995, 45.), (-179.999999, -45.),
+            (0., 90.), (0., -90.), (-180., -90.), (179.999999, 89.999999), (45., 89.995), (45., -89.995),
+        ];
+        for (longitude, latitude) in edges {
+            let exact = GeoPoint::new(longitude, latitude).unwrap();
+            for (precision, cell) in COARSENED {
+                for post_id in [FIRST, SECOND, "post-1", "post-2"] {
+                    let public = precision.public_location(exact, post_id);
+                    assert!((-180. ..180.).contains(&public.longitude()), "{exact} {public}");
+                    assert!((-90. ..=90.).contains(&public.latitude()), "{exact} {public}");
+                    assert!(within_cell(exact, public, cell), "{precision:?} {exact} {public}");
+                }
+            }
+        }
+        // A spot at the far end of the last cell before the antimeridian wraps to -180.
+        let exact = GeoPoint::new(179.999999, 10.).unwrap();
+        let wrapped = (0..100_000)
+            .map(|i| LocationPrecision::Neighborhood.public_location(exact, &format!("post-{i}")))
+            .find(|it| it.longitude() < 0.)
+            .unwrap();
+        assert_eq!(wrapped.longitude(), -180.);
+        assert!(within_cell(exact, wrapped, 0.01));
+    }
+
+    proptest! {
+        #[test]
+        fn public_location_stays_within_a_cell(longitude in -180f64..=180., latitude in -90f64..=90., post_id in "[a-z0-9-]{1,36}") {
+            let exact = GeoPoint::new(longitude, latitude).unwrap();
+            for (precision, cell) in COARSENED {
+                let public = precision.public_location(exact, &post_id);
+                prop_assert!(within_cell(exact, public, cell), "{:?} {} {}", precision, exact, public);
+                prop_assert_eq!(public, precision.public_location(exact, &post_id));
+            }
+        }
+    }
+}
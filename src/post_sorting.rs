@@ -2,56 +2,116 @@ use std::{cmp::Ordering, collections::HashMap, time::{Duration, SystemTime}};
 
 use aws_sdk_dynamodb::types::AttributeValue;
 
+use crate::{gazetteer::Place, geo::GeoPoint, post_model::{place_from_attribute, LocationPrecision}};
+
+/// A post as ranked by `recommend_posts`. `location` is the public, coarsened location,
+/// so rankings can't reveal more than `get_info` does.
 #[derive(Debug, Clone)]
 pub struct Post {
     pub likes: f64,
     pub time_since_created: f64,
-    pub lat: f64,
-    pub long: f64,
-    pub content_id: String,
+    pub location: GeoPoint,
+    pub place: Option<Place>,
+    pub id: String,
 }
 
 impl Post {
-    pub fn distance(&self, current_long: f64, current_lat: f64) -> f64 {
-        ((self.long-current_long).powi(2)+(self.lat-current_lat).powi(2)).sqrt()
+    pub fn distance(&self, current: GeoPoint) -> f64 {
+        self.location.distance(current)
     }
 
-    pub fn weight(&self, current_long: f64, current_lat: f64) -> f64 {
-        self.likes / (self.distance(current_long, current_lat) + self.time_since_created)
+    pub fn weight(&self, current: GeoPoint) -> f64 {
+        self.likes / (self.distance(current) + self.time_since_created)
     }
 
     pub fn from_db(map: HashMap<String, AttributeValue>) -> Option<Self> {
-        let likes = map.get("likes").map(|it| it.as_n().unwrap().parse().unwrap()).unwrap_or(0.);
-        let Some(timestamp) = map.get("date") else { return None; };
-        let timestamp: u64 = timestamp.as_n().unwrap().parse().unwrap();
-        let time_since_created = SystemTime::UNIX_EPOCH.elapsed().unwrap()-Duration::from_millis(timestamp);
+        let likes = match map.get("likes") {
+            Some(likes) => likes.as_n().ok()?.parse().ok()?,
+            None => 0.,
+        };
+        // Scheduled posts count from when they went live.
+        let timestamp = map.get("publish_at").or(map.get("date"))?;
+        let timestamp: u64 = timestamp.as_n().ok()?.parse().ok()?;
+        // A timestamp ahead of this clock counts as just created.
+        let time_since_created = SystemTime::UNIX_EPOCH.elapsed().ok()?.saturating_sub(Duration::from_millis(timestamp));
         let time_since_created = time_since_created.as_secs_f32() as f64/60./60.;
-        let Some(location) = map.get("location") else { return None; };
-        let location = location.as_s().unwrap().clone();
-        let long: f64 = location.split(",").nth(0).unwrap().parse().unwrap();
-        let lat: f64 = location.split(",").nth(1).unwrap().parse().unwrap();
-        let Some(content_id) = map.get("id") else { return None; };
-        let content_id = content_id.as_s().unwrap().clone();
+        let location = map.get("location")?;
+        let location = location.as_s().ok()?.parse().ok()?;
+        let id = map.get("id")?;
+        let id = id.as_s().ok()?.clone();
+        // Posts from before precision settings hold the exact location, see `PostRecord::from_item`.
+        let location = if map.contains_key("location_precision") {
+            location
+        } else {
+            LocationPrecision::LEGACY.public_location(location, &id)
+        };
         Some(Self {
             likes,
             time_since_created,
-            long,
-            lat,
-            content_id, 
+            location,
+            place: map.get("place").and_then(place_from_attribute),
+            id,
         })
     }
 }
 
-pub fn sort_posts_by_weight(posts: &mut [Post], current_long: f64, current_lat: f64) {
+pub fn sort_posts_by_weight(posts: &mut [Post], current: GeoPoint) {
     posts.sort_by(|a, b| {
-        b.weight(current_long, current_lat).partial_cmp(&a.weight(current_long, current_lat))
+        b.weight(current).partial_cmp(&a.weight(current))
             .unwrap_or(Ordering::Equal)
     });
 }
 
-pub fn sort_posts_by_distance(posts: &mut [Post], current_long: f64, current_lat: f64) {
+pub fn sort_posts_by_distance(posts: &mut [Post], current: GeoPoint) {
     posts.sort_by(|a, b| {
-        a.distance(current_long, current_lat).partial_cmp(&b.distance(current_long, current_lat))
+        a.distance(current).partial_cmp(&b.distance(current))
             .unwrap_or(Ordering::Equal)
     });
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(fields: &[(&str, AttributeValue)]) -> HashMap<String, AttributeValue> {
+        let mut item: HashMap<_, _> = [
+            ("id".to_string(), AttributeValue::S("post".into())),
+            ("date".to_string(), AttributeValue::N("0".into())),
+            ("location".to_string(), AttributeValue::S("13.4,52.5".into())),
+            ("location_precision".to_string(), AttributeValue::S("exact".into())),
+        ].into();
+        for (name, value) in fields {
+            item.insert(name.to_string(), value.clone());
+        }
+        item
+    }
+
+    #[test]
+    fn future_timestamp_counts_as_just_created() {
+        let future = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap() + Duration::from_secs(3600);
+        let post = Post::from_db(item(&[("date", AttributeValue::N(future.as_millis().to_string()))])).unwrap();
+        assert_eq!(post.time_since_created, 0.);
+        let post = Post::from_db(item(&[("publish_at", AttributeValue::N(future.as_millis().to_string()))])).unwrap();
+        assert_eq!(post.time_since_created, 0.);
+    }
+
+    #[test]
+    fn malformed_attributes_are_skipped() {
+        let post = Post::from_db(item(&[])).unwrap();
+        assert_eq!(post.likes, 0.);
+        assert!(post.time_since_created > 0.);
+        assert_eq!(Post::from_db(item(&[("likes", AttributeValue::N("12".into()))])).unwrap().likes, 12.);
+        for fields in [
+            [("likes", AttributeValue::S("12".into()))],
+            [("likes", AttributeValue::N("many".into()))],
+            [("date", AttributeValue::S("0".into()))],
+            [("date", AttributeValue::N("-1".into()))],
+            [("id", AttributeValue::N("1".into()))],
+            [("location", AttributeValue::S("north".into()))],
+        ] {
+            assert!(Post::from_db(item(&fields)).is_none(), "{fields:?}");
+        }
+        let mut missing_date = item(&[]);
+        missing_date.remove("date");
+        assert!(Post::from_db(missing_date).is_none());
+    }
+}
@@ -0,0 +1,191 @@
+use std::{collections::HashMap, sync::{Arc, Mutex, OnceLock}, time::{Duration, SystemTime}};
+
+use aws_sdk_dynamodb::{operation::put_item::PutItemError, types::AttributeValue};
+use lambda_http::{request::RequestContext, Error, Request, RequestExt};
+
+use crate::info_upload::DynamoDBClient;
+
+const RATE_LIMIT_TABLE: &str = "SocialMediaRateLimits";
+/// How often a conditional write may lose a race against a concurrent request before giving up.
+const MAX_ATTEMPTS: usize = 3;
+
+/// A token bucket: up to `burst` requests at once, refilled at `per_minute` requests per minute.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Bucket {
+    tokens: f64,
+    /// Unix millis of the last refill.
+    updated_at: u64,
+}
+
+impl Bucket {
+    /// Refills the bucket up to `now` and takes one token.
+    /// Returns how long to wait if the bucket is empty.
+    fn take(self, limit: RateLimit, now: u64) -> Result<Self, Duration> {
+        let per_milli = limit.per_minute as f64 / 60_000.;
+        let elapsed = now.saturating_sub(self.updated_at) as f64;
+        let tokens = (self.tokens + elapsed * per_milli).min(limit.burst as f64);
+        if tokens < 1. {
+            return Err(Duration::from_millis(((1. - tokens) / per_milli).ceil() as u64));
+        }
+        Ok(Self { tokens: tokens - 1., updated_at: now })
+    }
+}
+
+type Buckets = Arc<Mutex<HashMap<String, Bucket>>>;
+
+pub enum RateLimiter {
+    /// Buckets live in the `SocialMediaRateLimits` table, updated with conditional writes so
+    /// concurrent Lambda instances share them. Idle buckets expire through the `expires` TTL attribute.
+    DynamoDB(DynamoDBClient),
+    /// Only limits requests inside this process, for tests and local runs.
+    Memory(Buckets),
+}
+
+impl RateLimiter {
+    /// Uses the in-memory limiter when `RATE_LIMITER=memory`, DynamoDB otherwise.
+    pub async fn from_env() -> Result<Self, Error> {
+        if std::env::var("RATE_LIMITER").is_ok_and(|it| it == "memory") {
+            static MEMORY: OnceLock<Buckets> = OnceLock::new();
+            return Ok(Self::Memory(MEMORY.get_or_init(Default::default).clone()));
+        }
+        Ok(Self::DynamoDB(DynamoDBClient::new().await?))
+    }
+
+    /// Takes a token from the bucket for `key`.
+    /// Returns `Some(retry_after)` if the request has to be rejected.
+    pub async fn check(&self, key: &str, limit: RateLimit) -> Result<Option<Duration>, Error> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as u64;
+        let full = Bucket { tokens: limit.burst as f64, updated_at: now };
+        match self {
+            Self::DynamoDB(client) => {
+                for _ in 0..MAX_ATTEMPTS {
+                    let item = client.client.get_item()
+                        .table_name(RATE_LIMIT_TABLE)
+                        .key("id", AttributeValue::S(key.to_string()))
+                        .consistent_read(true)
+                        .send()
+                        .await?
+                        .item;
+                    let previous = item.as_ref().and_then(|item| Some(Bucket {
+                        tokens: item.get("tokens")?.as_n().ok()?.parse().ok()?,
+                        updated_at: item.get("updated_at")?.as_n().ok()?.parse().ok()?,
+                    }));
+                    let bucket = match previous.unwrap_or(full).take(limit, now) {
+                        Ok(bucket) => bucket,
+                        Err(retry_after) => return Ok(Some(retry_after)),
+                    };
+                    let refill_millis = limit.burst as u64 * 60_000 / limit.per_minute.max(1) as u64;
+                    let mut request = client.client.put_item()
+                        .table_name(RATE_LIMIT_TABLE)
+                        .item("id", AttributeValue::S(key.to_string()))
+                        .item("tokens", AttributeValue::N(bucket.tokens.to_string()))
+                        .item("updated_at", AttributeValue::N(bucket.updated_at.to_string()))
+                        .item("expires", AttributeValue::N(((now + refill_millis) / 1000 + 1).to_string()));
+                    request = match previous {
+                        Some(previous) => request
+                            .condition_expression("updated_at = :previous")
+                            .expression_attribute_values(":previous", AttributeValue::N(previous.updated_at.to_string())),
+                        None => request.condition_expression("attribute_not_exists(id)"),
+                    };
+                    match request.send().await {
+                        Ok(_) => return Ok(None),
+                        Err(e) => match e.into_service_error() {
+                            PutItemError::ConditionalCheckFailedException(_) => continue,
+                            e => return Err(e.into()),
+                        },
+                    }
+                }
+                // Lost every race against other requests for the same key, so it is clearly busy.
+                Ok(Some(Duration::from_secs(1)))
+            }
+            Self::Memory(buckets) => {
+                let mut buckets = buckets.lock().unwrap();
+                let bucket = buckets.get(key).copied().unwrap_or(full);
+                match bucket.take(limit, now) {
+                    Ok(bucket) => {
+                        buckets.insert(key.to_string(), bucket);
+                        Ok(None)
+                    }
+                    Err(retry_after) => Ok(Some(retry_after)),
+                }
+            }
+        }
+    }
+}
+
+/// Key of the bucket for the source IP of a request, `None` if the IP is unknown so
+/// such requests don't all end up sharing one bucket.
+pub fn ip_rate_limit_key(event: &Request) -> Option<String> {
+    let source_ip = match event.request_context_ref() {
+        Some(RequestContext::ApiGatewayV2(context)) => context.http.source_ip.clone(),
+        Some(RequestContext::ApiGatewayV1(context)) => context.identity.source_ip.clone(),
+        _ => None,
+    };
+    let source_ip = source_ip.or_else(|| {
+        let forwarded = event.headers().get("X-Forwarded-For")?.to_str().ok()?;
+        Some(forwarded.split(',').next()?.trim().to_string())
+    });
+    Some(format!("ip#{}", source_ip.filter(|it| !it.is_empty())?))
+}
+
+/// Key of the bucket for an authenticated caller, checked after authentication.
+pub fn user_rate_limit_key(username: &str) -> String {
+    format!("user#{username}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit { burst: 3, per_minute: 60 };
+
+    #[test]
+    fn takes_tokens_until_empty() {
+        let mut bucket = Bucket { tokens: 3., updated_at: 1_000 };
+        for remaining in [2., 1., 0.] {
+            bucket = bucket.take(LIMIT, 1_000).unwrap();
+            assert_eq!(bucket.tokens, remaining);
+        }
+        // One token per second at 60 per minute.
+        assert_eq!(bucket.take(LIMIT, 1_000).unwrap_err(), Duration::from_secs(1));
+        assert_eq!(bucket.take(LIMIT, 1_250).unwrap_err(), Duration::from_millis(750));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let bucket = Bucket { tokens: 0., updated_at: 1_000 };
+        let bucket = bucket.take(LIMIT, 3_000).unwrap();
+        assert_eq!((bucket.tokens, bucket.updated_at), (1., 3_000));
+        // Half a token is not enough.
+        assert!(Bucket { tokens: 0., updated_at: 1_000 }.take(LIMIT, 1_500).is_err());
+    }
+
+    #[test]
+    fn refill_is_capped_at_burst() {
+        let bucket = Bucket { tokens: 1., updated_at: 0 };
+        let bucket = bucket.take(LIMIT, 3_600_000).unwrap();
+        assert_eq!(bucket.tokens, 2.);
+    }
+
+    #[test]
+    fn clock_going_backwards_does_not_refill() {
+        let bucket = Bucket { tokens: 0., updated_at: 5_000 };
+        assert_eq!(bucket.take(LIMIT, 1_000).unwrap_err(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn unknown_source_ip_has_no_bucket() {
+        let event = lambda_http::http::Request::builder().body(lambda_http::Body::Empty).unwrap();
+        assert_eq!(ip_rate_limit_key(&event), None);
+        let event = lambda_http::http::Request::builder().header("X-Forwarded-For", "203.0.113.7, 10.0.0.1").body(lambda_http::Body::Empty).unwrap();
+        assert_eq!(ip_rate_limit_key(&event).as_deref(), Some("ip#203.0.113.7"));
+        let event = lambda_http::http::Request::builder().header("X-Forwarded-For", " ").body(lambda_http::Body::Empty).unwrap();
+        assert_eq!(ip_rate_limit_key(&event), None);
+    }
+}
@@ -1,64 +1,107 @@
+use std::collections::VecDeque;
+
 use aws_sdk_dynamodb::types::AttributeValue;
-use lambda_http::{Body, Error, Request, RequestExt, Response};
+use lambda_http::{Body, Request, RequestExt, Response};
+
+use crate::{api_error::ApiError, auth::Caller, geo::{geohash_attribute, GeoPoint, GEOHASH_PRECISIONS}, info_upload::DynamoDBClient, post_download::can_view, post_model::PostStatus, post_sorting::{sort_posts_by_distance, sort_posts_by_weight, Post}};
 
-use crate::{info_upload::{get_region_i64, DynamoDBClient}, post_sorting::{sort_posts_by_distance, sort_posts_by_weight, Post}};
+const DEFAULT_RADIUS_KM: f64 = 25.;
+const MAX_RADIUS_KM: f64 = 150.;
+/// The search starts at the finest geohash precision covering the radius in at most this many cells.
+const MAX_START_CELLS: usize = 16;
+/// Newest posts read from each geohash cell. A cell that has more is split into its finer cells.
+const MAX_POSTS_PER_CELL: i32 = 100;
+/// Most index queries per request, so a dense area can't fan out without bound.
+const MAX_QUERIES: usize = 64;
 
-pub async fn recommend_posts(event: Request) -> Result<Response<Body>, Error> {
+/// Recommends posts around `location`, as a list of post ids in ranking order, or with
+/// `format=detailed` as `[{"id", "location", "place"}]`. Posts are looked up through the
+/// geohash indexes (`gh3-index` to `gh6-index`, partitioned by cell, sorted by `date` and
+/// projecting all attributes).
+pub async fn recommend_posts(event: Request) -> Result<Response<Body>, ApiError> {
     let params = event.query_string_parameters();
     let Some(location) = params.first("location") else {
-        return Ok(Response::builder()
-            .status(400)
-            .body(Body::from("400 - No location"))
-            .unwrap());
+        return Err(ApiError::bad_request("missing_parameter", "location is required"));
     };
     let sorting = params.first("sort_by").unwrap_or("weight");
-    let Some((region_long, region_lat)) = get_region_i64(location) else {
-        return Ok(Response::builder()
-            .status(400)
-            .body(Body::from("400 - Invalid location"))
-            .unwrap());
+    let detailed = match params.first("format") {
+        None | Some("ids") => false,
+        Some("detailed") => true,
+        Some(_) => return Err(ApiError::bad_request("invalid_format", "format must be \"ids\" or \"detailed\"")),
+    };
+    let location = match location.parse::<GeoPoint>() {
+        Ok(location) => location,
+        Err(e) => return Err(ApiError::bad_request("invalid_location", e.to_string())),
+    };
+    let radius_km = match params.first("radius_km").map(str::parse::<f64>) {
+        None => DEFAULT_RADIUS_KM,
+        Some(Ok(radius)) if radius > 0. && radius <= MAX_RADIUS_KM => radius,
+        Some(_) => return Err(ApiError::bad_request("invalid_radius", format!("radius_km must be a number between 0 and {MAX_RADIUS_KM}"))),
     };
-    let longitude: f64 = location.split(",").nth(0).unwrap().parse().unwrap();
-    let latitude: f64 = location.split(",").nth(1).unwrap().parse().unwrap();
     let client = DynamoDBClient::new().await?;
-    let posts = client.client.scan()
-        .table_name("SocialMediaPosts")
-        .filter_expression("
-        (#rlong = :long AND #rlat = :lat) OR
-        (#rlong = :longP AND #rlat = :lat) OR
-        (#rlong = :long AND #rlat = :latP) OR
-        (#rlong = :longP AND #rlat = :latP) OR
-        (#rlong = :longN AND #rlat = :lat) OR
-        (#rlong = :long AND #rlat = :latN) OR
-        (#rlong = :longN AND #rlat = :latN) OR
-        (#rlong = :longP AND #rlat = :latN) OR
-        (#rlong = :longN AND #rlat = :latP)
-        ")
-        .expression_attribute_names("#rlong", "r_long")
-        .expression_attribute_names("#rlat", "r_lat")
-        .expression_attribute_values(":long", AttributeValue::S(format!("{region_long}")))
-        .expression_attribute_values(":longP", AttributeValue::S(format!("{}", region_long+1)))
-        .expression_attribute_values(":longN", AttributeValue::S(format!("{}", region_long-1)))
-        .expression_attribute_values(":lat", AttributeValue::S(format!("{region_lat}")))
-        .expression_attribute_values(":latP", AttributeValue::S(format!("{}", region_lat+1)))
-        .expression_attribute_values(":latN", AttributeValue::S(format!("{}", region_lat-1)))
-        .send().await?.items.unwrap_or_default();
-    let mut posts: Vec<Post> = posts.into_iter().filter_map(|it| {
-        Post::from_db(it)
-    }).collect();
+    let caller = Caller::of(&event);
+
+    // Start with the finest cells that cover the radius in a handful of queries, and split
+    // cells with more posts than one query returns so dense areas aren't cut to the newest posts.
+    let start = GEOHASH_PRECISIONS.iter().rev()
+        .copied()
+        .find(|&precision| location.geohash_cover(precision, radius_km).len() <= MAX_START_CELLS)
+        .unwrap_or(GEOHASH_PRECISIONS[0]);
+    let mut cells = location.geohash_cover(start, radius_km).into_iter().collect::<VecDeque<_>>();
+    let mut queries = 0;
+    let mut posts = Vec::new();
+    while let Some(cell) = cells.pop_front() {
+        let precision = cell.len();
+        let items = client.client.query()
+            .table_name("SocialMediaPosts")
+            .index_name(format!("{}-index", geohash_attribute(precision)))
+            .key_condition_expression("#cell = :cell")
+            .expression_attribute_names("#cell", geohash_attribute(precision))
+            .expression_attribute_values(":cell", AttributeValue::S(cell.clone()))
+            .filter_expression("attribute_not_exists(#status) OR #status = :published")
+            .expression_attribute_names("#status", "status")
+            .expression_attribute_values(":published", AttributeValue::S(PostStatus::Published.as_str().into()))
+            .scan_index_forward(false)
+            .limit(MAX_POSTS_PER_CELL)
+            .send().await?;
+        queries += 1;
+        // A last evaluated key means the cell had more posts than the limit.
+        if items.last_evaluated_key.is_some() && GEOHASH_PRECISIONS.contains(&(precision + 1)) {
+            let finer = location.geohash_cover(precision + 1, radius_km).into_iter()
+                .filter(|it| it.starts_with(&cell))
+                .collect::<Vec<_>>();
+            if queries + cells.len() + finer.len() <= MAX_QUERIES {
+                cells.extend(finer);
+                continue;
+            }
+        }
+        posts.extend(items.items.unwrap_or_default().into_iter().filter(|it| can_view(it, &caller)));
+    }
+    let mut posts: Vec<Post> = posts.into_iter()
+        .filter_map(|it| {
+            Post::from_db(it)
+        })
+        .filter(|it| it.location.distance_km(location) <= radius_km)
+        .collect();
     if sorting == "location" {
-        sort_posts_by_distance(&mut posts, longitude, latitude);
+        sort_posts_by_distance(&mut posts, location);
     } else {
-        sort_posts_by_weight(&mut posts, longitude, latitude);
+        sort_posts_by_weight(&mut posts, location);
     }
 
-    let infos = posts.into_iter().map(|item|{ 
-        item.content_id
+    let infos = posts.into_iter().map(|item| if detailed {
+        serde_json::json!({
+            "id": item.id,
+            "location": item.location.to_string(),
+            "place": item.place,
+        })
+    } else {
+        serde_json::json!(item.id)
     }).collect::<Vec<_>>();
-    
-    return Ok(Response::builder()
+
+    Ok(Response::builder()
         .status(200)
         .header("Content-Type", "application/json")
-        .body(Body::from(format!("[{}]", infos.iter().map(|it| format!("{it:?}")).collect::<Vec<_>>().join(", "))))
-        .unwrap());
-}
\ No newline at end of file
+        .body(Body::from(serde_json::to_string(&infos)?))
+        .unwrap())
+}
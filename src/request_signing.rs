@@ -0,0 +1,131 @@
+use lambda_http::{Request, RequestExt};
+use openssl::{error::ErrorStack, hash::{hash, MessageDigest}};
+
+/// Header a client uses to tell which payload format it signed.
+pub const SIGNATURE_VERSION_HEADER: &str = "X-Auth-Version";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureVersion {
+    /// `username || nonce`. Sent by clients without an `X-Auth-Version` header;
+    /// only accepted while `ALLOW_LEGACY_SIGNATURES` is not set to `false`.
+    V1,
+    /// The canonical request built by [`canonical_request`].
+    V2,
+}
+
+impl SignatureVersion {
+    /// Reads the version the request was signed with, `None` if it is unknown or no longer accepted.
+    pub fn from_request(event: &Request) -> Option<Self> {
+        match event.headers().get(SIGNATURE_VERSION_HEADER).map(|it| it.to_str()) {
+            None if legacy_signatures_allowed() => Some(Self::V1),
+            None => None,
+            Some(Ok("2")) => Some(Self::V2),
+            Some(_) => None,
+        }
+    }
+}
+
+fn legacy_signatures_allowed() -> bool {
+    std::env::var("ALLOW_LEGACY_SIGNATURES").map_or(true, |it| it != "false")
+}
+
+/// Builds the bytes the client's signature has to cover.
+pub fn signed_payload(version: SignatureVersion, event: &Request, username: &str, nonce: &str) -> Result<Vec<u8>, ErrorStack> {
+    match version {
+        SignatureVersion::V1 => {
+            let mut payload = vec![];
+            payload.append(&mut username.as_bytes().to_vec());
+            payload.append(&mut nonce.as_bytes().to_vec());
+            Ok(payload)
+        }
+        SignatureVersion::V2 => Ok(canonical_request(event, username, nonce)?.into_bytes()),
+    }
+}
+
+/// The newline separated canonical form of a request:
+///
+/// ```text
+/// SMP-SIG-V2
+/// <method>
+/// <raw path>
+/// <query string with its `key=value` pairs sorted, still percent-encoded as sent>
+/// <lowercase hex SHA-256 of the body>
+/// <username>
+/// <nonce>
+/// ```
+pub fn canonical_request(event: &Request, username: &str, nonce: &str) -> Result<String, ErrorStack> {
+    let body_hash = hash(MessageDigest::sha256(), event.body().as_ref())?;
+    let body_hash = body_hash.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+    Ok([
+        "SMP-SIG-V2",
+        event.method().as_str(),
+        event.raw_http_path(),
+        &canonical_query(event.uri().query().unwrap_or_default()),
+        &body_hash,
+        username,
+        nonce,
+    ].join("\n"))
+}
+
+fn canonical_query(query: &str) -> String {
+    let mut pairs = query.split('&')
+        .filter(|it| !it.is_empty())
+        .map(|it| it.split_once('=').unwrap_or((it, "")))
+        .collect::<Vec<_>>();
+    pairs.sort();
+    pairs.into_iter().map(|(key, value)| format!("{key}={value}")).collect::<Vec<_>>().join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use lambda_http::Body;
+
+    use super::*;
+
+    /// A request as API Gateway hands it over, with the raw path set next to the URI.
+    fn request(method: &str, uri: &str, body: &str) -> Request {
+        let event = lambda_http::http::Request::builder().method(method).uri(uri).body(Body::from(body)).unwrap();
+        let path = event.uri().path().to_string();
+        event.with_raw_http_path(path)
+    }
+
+    #[test]
+    fn canonical_request_known_answer() {
+        let event = request("POST", "https://api.example.com/posts/abc?tag=b%20c&flag&lang=en&tag=a", "hello");
+        assert_eq!(canonical_request(&event, "alice", "1700000000000.abcdefghijklmnop").unwrap(), "SMP-SIG-V2\n\
+            POST\n\
+            /posts/abc\n\
+            flag=&lang=en&tag=a&tag=b%20c\n\
+            2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824\n\
+            alice\n\
+            1700000000000.abcdefghijklmnop");
+    }
+
+    #[test]
+    fn canonical_request_without_query_or_body() {
+        let event = request("GET", "https://api.example.com/recommendations", "");
+        assert_eq!(canonical_request(&event, "bob", "1700000000000.abcdefghijklmnop").unwrap(), "SMP-SIG-V2\n\
+            GET\n\
+            /recommendations\n\
+            \n\
+            e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\n\
+            bob\n\
+            1700000000000.abcdefghijklmnop");
+        // A bare `?` and stray `&`s are an empty query too.
+        let event = request("GET", "https://api.example.com/recommendations?&&", "");
+        assert!(canonical_request(&event, "bob", "n").unwrap().contains("/recommendations\n\ne3b0"));
+    }
+
+    #[test]
+    fn canonical_query_normalizes_keys_without_value() {
+        assert_eq!(canonical_query("b&a=1"), "a=1&b=");
+        assert_eq!(canonical_query("b=&a"), "a=&b=");
+        assert_eq!(canonical_query(""), "");
+    }
+
+    #[test]
+    fn v1_payload_is_username_then_nonce() {
+        let event = request("GET", "https://api.example.com/get-info", "");
+        assert_eq!(signed_payload(SignatureVersion::V1, &event, "alice", "1700000000000").unwrap(), b"alice1700000000000");
+    }
+}
@@ -0,0 +1,160 @@
+use std::{collections::HashMap, future::Future, pin::Pin};
+
+use lambda_http::{http::Method, Body, Request, Response};
+
+use crate::{api_error::ApiError, rate_limit::RateLimit};
+
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Result<Response<Body>, ApiError>> + Send>>;
+pub type Handler = fn(Request) -> HandlerFuture;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Auth {
+    /// Every request must pass the certificate and signature check.
+    Required,
+    /// Like `Required`, but session tokens are not accepted.
+    Certificate,
+    /// Requests without credentials are served anonymously; requests that carry
+    /// credentials still have to pass the full check.
+    Optional,
+}
+
+pub struct Route {
+    pub method: Method,
+    /// Path with `{name}` placeholders for single segments, e.g. `/posts/{id}`.
+    /// Matched values are handed to the handler as path parameters.
+    pub pattern: &'static str,
+    pub auth: Auth,
+    /// Applied per user, or per source IP to anonymous callers, see
+    /// [`user_rate_limit_key`](crate::rate_limit::user_rate_limit_key) and [`ip_rate_limit_key`](crate::rate_limit::ip_rate_limit_key).
+    pub rate_limit: Option<RateLimit>,
+    pub handler: Handler,
+}
+
+pub enum RouteMatch<'a> {
+    Found(&'a Route, HashMap<String, String>),
+    /// The path exists but not for this method; holds the methods it does accept.
+    MethodNotAllowed(Vec<Method>),
+    NotFound,
+}
+
+pub fn match_route<'a>(routes: &'a [Route], method: &Method, path: &str) -> RouteMatch<'a> {
+    let mut allowed = vec![];
+    for route in routes {
+        let Some(params) = match_pattern(route.pattern, path) else {
+            continue;
+        };
+        if route.method == *method {
+            return RouteMatch::Found(route, params);
+        }
+        allowed.push(route.method.clone());
+    }
+    if allowed.is_empty() {
+        RouteMatch::NotFound
+    } else {
+        RouteMatch::MethodNotAllowed(allowed)
+    }
+}
+
+fn match_pattern(pattern: &str, path: &str) -> Option<HashMap<String, String>> {
+    let mut pattern_segments = pattern.trim_matches('/').split('/');
+    let mut path_segments = path.trim_matches('/').split('/');
+    let mut params = HashMap::new();
+    loop {
+        match (pattern_segments.next(), path_segments.next()) {
+            (None, None) => return Some(params),
+            (Some(expected), Some(segment)) => {
+                if let Some(name) = expected.strip_prefix('{').and_then(|it| it.strip_suffix('}')) {
+                    if segment.is_empty() {
+                        return None;
+                    }
+                    params.insert(name.to_string(), segment.to_string());
+                } else if expected != segment {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Builds the `Allow` header value for a 405 response.
+pub fn allow_header(methods: &[Method]) -> String {
+    methods.iter().map(Method::as_str).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(method: Method, pattern: &'static str) -> Route {
+        Route { method, pattern, auth: Auth::Optional, rate_limit: None, handler: |_| Box::pin(async { unreachable!() }) }
+    }
+
+    fn routes() -> Vec<Route> {
+        vec![
+            route(Method::POST, "/post-info"),
+            route(Method::GET, "/posts/{id}"),
+            route(Method::PATCH, "/posts/{id}"),
+            route(Method::DELETE, "/posts/{id}"),
+            route(Method::GET, "/posts/{id}/media/{index}"),
+        ]
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn extracts_path_parameters() {
+        assert_eq!(match_pattern("/posts/{id}", "/posts/abc"), Some(params(&[("id", "abc")])));
+        assert_eq!(match_pattern("/posts/{id}/media/{index}", "/posts/abc/media/2"), Some(params(&[("id", "abc"), ("index", "2")])));
+        assert_eq!(match_pattern("/post-info", "/post-info"), Some(params(&[])));
+        assert_eq!(match_pattern("/posts/{id}", "/posts/abc/media"), None);
+        assert_eq!(match_pattern("/posts/{id}/media/{index}", "/posts/abc/likes/2"), None);
+        assert_eq!(match_pattern("/post-info", "/post-media"), None);
+    }
+
+    #[test]
+    fn rejects_empty_segments() {
+        assert_eq!(match_pattern("/posts/{id}", "/posts/"), None);
+        assert_eq!(match_pattern("/posts/{id}", "/posts"), None);
+        assert_eq!(match_pattern("/posts/{id}/media/{index}", "/posts//media/0"), None);
+        assert_eq!(match_pattern("/posts/{id}/media/{index}", "/posts/abc/media/"), None);
+    }
+
+    #[test]
+    fn ignores_leading_and_trailing_slashes() {
+        assert_eq!(match_pattern("/posts/{id}", "/posts/abc/"), Some(params(&[("id", "abc")])));
+        assert_eq!(match_pattern("/posts/{id}", "posts/abc"), Some(params(&[("id", "abc")])));
+        assert_eq!(match_pattern("/post-info", "/post-info/"), Some(params(&[])));
+    }
+
+    #[test]
+    fn finds_route_by_method() {
+        let routes = routes();
+        let RouteMatch::Found(route, params) = match_route(&routes, &Method::PATCH, "/posts/abc") else {
+            panic!("PATCH /posts/abc should match");
+        };
+        assert_eq!((&route.method, route.pattern), (&Method::PATCH, "/posts/{id}"));
+        assert_eq!(params["id"], "abc");
+    }
+
+    #[test]
+    fn lists_every_allowed_method() {
+        let routes = routes();
+        let RouteMatch::MethodNotAllowed(allowed) = match_route(&routes, &Method::PUT, "/posts/abc") else {
+            panic!("PUT /posts/abc should not be allowed");
+        };
+        assert_eq!(allowed, [Method::GET, Method::PATCH, Method::DELETE]);
+        assert_eq!(allow_header(&allowed), "GET, PATCH, DELETE");
+        assert!(matches!(match_route(&routes, &Method::GET, "/post-info"), RouteMatch::MethodNotAllowed(allowed) if allowed == [Method::POST]));
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let routes = routes();
+        assert!(matches!(match_route(&routes, &Method::GET, "/nope"), RouteMatch::NotFound));
+        assert!(matches!(match_route(&routes, &Method::GET, "/posts/"), RouteMatch::NotFound));
+        assert!(matches!(match_route(&routes, &Method::GET, "/posts/abc/media"), RouteMatch::NotFound));
+    }
+}
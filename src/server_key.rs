@@ -0,0 +1,149 @@
+use std::{sync::{OnceLock, RwLock}, time::{Duration, Instant}};
+
+use lambda_http::{tracing, Error};
+use openssl::{base64, pkey::{PKey, Public}, x509::X509};
+
+const DEFAULT_KEYS_URL: &str = "https://social-media-account-provisioning-public-key.s3.us-west-2.amazonaws.com/server_public_key.der";
+const DEFAULT_TTL: Duration = Duration::from_secs(60 * 10);
+/// How long to wait before trying again when a refresh fails and we are still serving stale keys.
+const RETRY_AFTER_FAILURE: Duration = Duration::from_secs(30);
+
+struct CachedKeys {
+    keys: Vec<PKey<Public>>,
+    refresh_at: Instant,
+}
+
+/// Lives for as long as the Lambda container stays warm.
+static CACHE: RwLock<Option<CachedKeys>> = RwLock::new(None);
+
+/// Returns every currently active server public key.
+///
+/// Keys are loaded from the first configured source:
+/// - `SERVER_PUBLIC_KEYS`: the key list itself
+/// - `SERVER_PUBLIC_KEYS_FILE`: a path to a local file with the key list
+/// - `SERVER_PUBLIC_KEYS_URL`: a URL to fetch the key list from (defaults to the provisioning bucket)
+///
+/// A key list is one base64 DER public key per line, so the provisioning CA can publish
+/// the new key next to the old one while clients roll over. Lines starting with `#` are ignored.
+/// The list is cached and refreshed every `SERVER_PUBLIC_KEYS_TTL_SECS` seconds; if a refresh
+/// fails the previously loaded keys keep being used.
+pub async fn server_public_keys() -> Result<Vec<PKey<Public>>, Error> {
+    if let Some(cached) = CACHE.read().unwrap().as_ref() {
+        if Instant::now() < cached.refresh_at {
+            return Ok(cached.keys.clone());
+        }
+    }
+
+    match load_keys().await {
+        Ok(keys) => {
+            *CACHE.write().unwrap() = Some(CachedKeys {
+                keys: keys.clone(),
+                refresh_at: Instant::now() + ttl(),
+            });
+            Ok(keys)
+        }
+        Err(e) => {
+            let mut cache = CACHE.write().unwrap();
+            let Some(cached) = cache.as_mut() else {
+                return Err(e);
+            };
+            tracing::warn!(error = ?e, "failed to refresh server public keys, using cached keys");
+            cached.refresh_at = Instant::now() + RETRY_AFTER_FAILURE;
+            Ok(cached.keys.clone())
+        }
+    }
+}
+
+fn ttl() -> Duration {
+    std::env::var("SERVER_PUBLIC_KEYS_TTL_SECS").ok()
+        .and_then(|it| it.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TTL)
+}
+
+async fn load_keys() -> Result<Vec<PKey<Public>>, Error> {
+    let key_list = if let Ok(key_list) = std::env::var("SERVER_PUBLIC_KEYS") {
+        key_list
+    } else if let Ok(path) = std::env::var("SERVER_PUBLIC_KEYS_FILE") {
+        std::fs::read_to_string(path)?
+    } else {
+        let url = std::env::var("SERVER_PUBLIC_KEYS_URL").unwrap_or_else(|_| DEFAULT_KEYS_URL.to_string());
+        reqwest::get(url).await?.error_for_status()?.text().await?
+    };
+    parse_key_list(&key_list)
+}
+
+fn parse_key_list(key_list: &str) -> Result<Vec<PKey<Public>>, Error> {
+    let keys = key_list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| Ok(PKey::public_key_from_der(&base64::decode_block(line)?)?))
+        .collect::<Result<Vec<_>, Error>>()?;
+    if keys.is_empty() {
+        return Err("server public key list is empty".into());
+    }
+    Ok(keys)
+}
+
+/// Root CA certificates that client certificates may chain up to through `X-Auth-Chain` intermediates.
+/// Loaded once from PEM in `AUTH_ROOT_CERTS`, or from the file at `AUTH_ROOT_CERTS_FILE`; empty if neither is set.
+pub fn trusted_roots() -> &'static [X509] {
+    static ROOTS: OnceLock<Vec<X509>> = OnceLock::new();
+    ROOTS.get_or_init(|| {
+        let pem = if let Ok(pem) = std::env::var("AUTH_ROOT_CERTS") {
+            pem.into_bytes()
+        } else if let Ok(path) = std::env::var("AUTH_ROOT_CERTS_FILE") {
+            match std::fs::read(path) {
+                Ok(pem) => pem,
+                Err(e) => {
+                    tracing::error!(error = ?e, "failed to read root certificates");
+                    return vec![];
+                }
+            }
+        } else {
+            return vec![];
+        };
+        X509::stack_from_pem(&pem).unwrap_or_else(|e| {
+            tracing::error!(error = ?e, "failed to parse root certificates");
+            vec![]
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded_key() -> (String, Vec<u8>) {
+        let der = PKey::generate_ed25519().unwrap().public_key_to_der().unwrap();
+        (base64::encode_block(&der), der)
+    }
+
+    #[test]
+    fn parses_multiple_keys() {
+        let ((first, first_der), (second, second_der)) = (encoded_key(), encoded_key());
+        let keys = parse_key_list(&format!("{first}\n{second}\n")).unwrap();
+        let ders = keys.iter().map(|it| it.public_key_to_der().unwrap()).collect::<Vec<_>>();
+        assert_eq!(ders, [first_der, second_der]);
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        let ((first, _), (second, _)) = (encoded_key(), encoded_key());
+        let list = format!("# current key\n{first}\n\n   \n  # next key, published ahead of the rollover\n  {second}  \n");
+        assert_eq!(parse_key_list(&list).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rejects_bad_keys() {
+        let (key, _) = encoded_key();
+        assert!(parse_key_list(&format!("{key}\nnot base64!")).is_err());
+        assert!(parse_key_list(&base64::encode_block(b"not a key")).is_err());
+    }
+
+    #[test]
+    fn rejects_empty_list() {
+        assert!(parse_key_list("").is_err());
+        assert!(parse_key_list("# no keys yet\n\n").is_err());
+    }
+}
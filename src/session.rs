@@ -0,0 +1,245 @@
+use std::time::{Duration, SystemTime};
+
+use lambda_http::{http::Method, Body, Request, Response};
+use openssl::{base64, hash::MessageDigest, memcmp, pkey::PKey, sign::Signer};
+
+use crate::{api_error::ApiError, auth::{AuthError, Caller}, cert_revocation::is_revoked, info_upload::DynamoDBClient};
+
+const DEFAULT_TTL: Duration = Duration::from_secs(60 * 15);
+const TOKEN_PREFIX: &str = "v1";
+
+/// What a session token may be used for. `read` covers `GET` requests, `write` everything else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+}
+
+impl Scope {
+    pub fn for_method(method: &Method) -> Self {
+        if method == Method::GET { Self::Read } else { Self::Write }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Claims {
+    /// Username the token was issued to.
+    pub sub: String,
+    /// [`cert_id`](crate::cert_revocation::cert_id) of the device certificate the session was
+    /// created with, so revoking the certificate also ends its sessions.
+    pub cert_id: String,
+    /// Unix millis.
+    pub iat: u64,
+    /// Unix millis.
+    pub exp: u64,
+    pub scope: Vec<Scope>,
+}
+
+#[derive(serde::Deserialize, Default)]
+struct SessionRequest {
+    scope: Option<Vec<Scope>>,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+fn ttl() -> Duration {
+    std::env::var("SESSION_TOKEN_TTL_SECS").ok()
+        .and_then(|it| it.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TTL)
+}
+
+/// `SESSION_TOKEN_SECRET`, empty if it is not set.
+fn secret() -> Vec<u8> {
+    std::env::var("SESSION_TOKEN_SECRET").unwrap_or_default().into_bytes()
+}
+
+/// HMAC-SHA256 of `message`. An empty secret means sessions are not configured; it is never used as a key.
+fn sign(message: &str, secret: &[u8]) -> Result<Vec<u8>, AuthError> {
+    if secret.is_empty() {
+        return Err(AuthError::SessionsUnavailable);
+    }
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    Ok(signer.sign_oneshot_to_vec(message.as_bytes())?)
+}
+
+fn encode_base64url(bytes: &[u8]) -> String {
+    base64::encode_block(bytes).replace('+', "-").replace('/', "_").trim_end_matches('=').to_string()
+}
+
+fn decode_base64url(text: &str) -> Option<Vec<u8>> {
+    let padding = "=".repeat((4 - text.len() % 4) % 4);
+    base64::decode_block(&(text.replace('-', "+").replace('_', "/") + &padding)).ok()
+}
+
+/// Issues a token of the form `v1.<base64url claims JSON>.<base64url HMAC-SHA256>`,
+/// keyed with `SESSION_TOKEN_SECRET`.
+pub fn issue_token(claims: &Claims) -> Result<String, AuthError> {
+    encode_token(claims, &secret())
+}
+
+fn encode_token(claims: &Claims, secret: &[u8]) -> Result<String, AuthError> {
+    let payload = format!("{TOKEN_PREFIX}.{}", encode_base64url(&serde_json::to_vec(claims).unwrap()));
+    let mac = sign(&payload, secret)?;
+    Ok(format!("{payload}.{}", encode_base64url(&mac)))
+}
+
+/// Checks a token's MAC, expiry and certificate, and that it carries `scope`.
+pub async fn verify_token(token: &str, scope: Scope) -> Result<Claims, AuthError> {
+    let claims = decode_token(token, scope, &secret())?;
+    let client = DynamoDBClient::new().await.map_err(AuthError::Internal)?;
+    if is_revoked(&client, &claims.cert_id).await.map_err(AuthError::Internal)? {
+        return Err(AuthError::CertificateRevoked);
+    }
+    Ok(claims)
+}
+
+/// Everything [`verify_token`] checks except revocation.
+fn decode_token(token: &str, scope: Scope, secret: &[u8]) -> Result<Claims, AuthError> {
+    let (payload, mac) = token.rsplit_once('.').ok_or(AuthError::InvalidToken)?;
+    let (prefix, claims) = payload.split_once('.').ok_or(AuthError::InvalidToken)?;
+    if prefix != TOKEN_PREFIX {
+        return Err(AuthError::InvalidToken);
+    }
+    let mac = decode_base64url(mac).ok_or(AuthError::InvalidToken)?;
+    let expected = sign(payload, secret)?;
+    if mac.len() != expected.len() || !memcmp::eq(&mac, &expected) {
+        return Err(AuthError::InvalidToken);
+    }
+    let claims = decode_base64url(claims)
+        .and_then(|it| serde_json::from_slice::<Claims>(&it).ok())
+        .ok_or(AuthError::InvalidToken)?;
+    if claims.exp <= now_millis() {
+        return Err(AuthError::ExpiredToken);
+    }
+    if !claims.scope.contains(&scope) {
+        return Err(AuthError::InsufficientScope);
+    }
+    Ok(claims)
+}
+
+/// Exchanges a certificate-signed request for a short-lived bearer token.
+/// The body may narrow the token to `{"scope": ["read"]}`; it defaults to every scope.
+pub async fn create_session(event: Request) -> Result<Response<Body>, ApiError> {
+    let Caller::User(identity) = Caller::of(&event) else {
+        unreachable!("session route requires a certificate");
+    };
+    let request = if event.body().is_empty() {
+        SessionRequest::default()
+    } else {
+        let Ok(request) = serde_json::from_slice::<SessionRequest>(event.body()) else {
+            return Err(ApiError::bad_request("invalid_body", "body must be {\"scope\": [...]}"));
+        };
+        request
+    };
+    let now = now_millis();
+    let claims = Claims {
+        sub: identity.username,
+        cert_id: identity.cert_id,
+        iat: now,
+        exp: now + ttl().as_millis() as u64,
+        scope: request.scope.unwrap_or(vec![Scope::Read, Scope::Write]),
+    };
+    let token = issue_token(&claims)?;
+    let body = serde_json::json!({
+        "token": token,
+        "expires_at": claims.exp,
+        "scope": claims.scope,
+    });
+
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"test secret";
+
+    fn claims(scope: Vec<Scope>) -> Claims {
+        let now = now_millis();
+        Claims { sub: "alice".into(), cert_id: "ab12:1f".into(), iat: now, exp: now + 60_000, scope }
+    }
+
+    #[test]
+    fn round_trips() {
+        let token = encode_token(&claims(vec![Scope::Read, Scope::Write]), SECRET).unwrap();
+        assert!(token.starts_with("v1."));
+        for scope in [Scope::Read, Scope::Write] {
+            let decoded = decode_token(&token, scope, SECRET).unwrap();
+            assert_eq!((decoded.sub.as_str(), decoded.cert_id.as_str()), ("alice", "ab12:1f"));
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_claims() {
+        let token = encode_token(&claims(vec![Scope::Read]), SECRET).unwrap();
+        let (payload, mac) = token.rsplit_once('.').unwrap();
+        let mut forged = claims(vec![Scope::Read, Scope::Write]);
+        forged.sub = "mallory".into();
+        let forged_payload = format!("v1.{}", encode_base64url(&serde_json::to_vec(&forged).unwrap()));
+        assert!(matches!(decode_token(&format!("{forged_payload}.{mac}"), Scope::Read, SECRET), Err(AuthError::InvalidToken)));
+        // Flipping a single character of the claims.
+        let mut tampered = payload.to_string().into_bytes();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert!(matches!(decode_token(&format!("{tampered}.{mac}"), Scope::Read, SECRET), Err(AuthError::InvalidToken)));
+    }
+
+    #[test]
+    fn rejects_tampered_mac() {
+        let token = encode_token(&claims(vec![Scope::Read]), SECRET).unwrap();
+        let (payload, mac) = token.rsplit_once('.').unwrap();
+        let mut mac = decode_base64url(mac).unwrap();
+        mac[0] ^= 1;
+        assert!(matches!(decode_token(&format!("{payload}.{}", encode_base64url(&mac)), Scope::Read, SECRET), Err(AuthError::InvalidToken)));
+        assert!(matches!(decode_token(&format!("{payload}.{}", encode_base64url(&mac[1..])), Scope::Read, SECRET), Err(AuthError::InvalidToken)));
+        assert!(matches!(decode_token(&format!("{payload}."), Scope::Read, SECRET), Err(AuthError::InvalidToken)));
+        assert!(matches!(decode_token(&format!("{payload}.!!"), Scope::Read, SECRET), Err(AuthError::InvalidToken)));
+        // Signed with another secret.
+        assert!(matches!(decode_token(&token, Scope::Read, b"other secret"), Err(AuthError::InvalidToken)));
+    }
+
+    #[test]
+    fn rejects_wrong_prefix() {
+        let token = encode_token(&claims(vec![Scope::Read]), SECRET).unwrap();
+        let (payload, _) = token.rsplit_once('.').unwrap();
+        let payload = payload.replacen("v1.", "v2.", 1);
+        // Correctly signed, so only the prefix is wrong.
+        let token = format!("{payload}.{}", encode_base64url(&sign(&payload, SECRET).unwrap()));
+        assert!(matches!(decode_token(&token, Scope::Read, SECRET), Err(AuthError::InvalidToken)));
+        assert!(matches!(decode_token("no-dots", Scope::Read, SECRET), Err(AuthError::InvalidToken)));
+    }
+
+    #[test]
+    fn rejects_expired() {
+        let mut expired = claims(vec![Scope::Read]);
+        expired.exp = now_millis() - 1;
+        let token = encode_token(&expired, SECRET).unwrap();
+        assert!(matches!(decode_token(&token, Scope::Read, SECRET), Err(AuthError::ExpiredToken)));
+    }
+
+    #[test]
+    fn rejects_missing_scope() {
+        let token = encode_token(&claims(vec![Scope::Read]), SECRET).unwrap();
+        assert!(matches!(decode_token(&token, Scope::Write, SECRET), Err(AuthError::InsufficientScope)));
+        let token = encode_token(&claims(vec![]), SECRET).unwrap();
+        assert!(matches!(decode_token(&token, Scope::Read, SECRET), Err(AuthError::InsufficientScope)));
+    }
+
+    #[test]
+    fn rejects_empty_secret() {
+        assert!(matches!(encode_token(&claims(vec![Scope::Read]), b""), Err(AuthError::SessionsUnavailable)));
+        let token = encode_token(&claims(vec![Scope::Read]), SECRET).unwrap();
+        assert!(matches!(decode_token(&token, Scope::Read, b""), Err(AuthError::SessionsUnavailable)));
+    }
+}
@@ -0,0 +1,78 @@
+use std::time::{Duration, SystemTime};
+
+use aws_sdk_dynamodb::{operation::update_item::UpdateItemError, types::AttributeValue};
+
+use crate::{api_error::ApiError, info_upload::DynamoDBClient};
+
+const UPLOADS_TABLE: &str = "SocialMediaUploads";
+/// How long an upload can stay unattached before someone has to request a new upload URL.
+pub const RESERVATION_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Records that `username` is about to upload `content_id`, so only they can attach it to a post.
+/// Unattached reservations expire through the `expires` TTL attribute.
+pub async fn reserve(client: &DynamoDBClient, content_id: &str, username: &str) -> Result<(), ApiError> {
+    let now = SystemTime::now();
+    client.client.put_item()
+        .table_name(UPLOADS_TABLE)
+        .item("content_id", AttributeValue::S(content_id.to_string()))
+        .item("username", AttributeValue::S(username.to_string()))
+        .item("created_at", AttributeValue::N(unix_secs(now).to_string()))
+        .item("expires", AttributeValue::N(unix_secs(now + RESERVATION_TTL).to_string()))
+        .condition_expression("attribute_not_exists(content_id)")
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Attaches an upload to `post_id`. Fails unless `username` reserved it, the reservation has
+/// not expired, and it is not attached to a post yet. Attached uploads no longer expire.
+pub async fn claim(client: &DynamoDBClient, content_id: &str, username: &str, post_id: &str) -> Result<(), ApiError> {
+    let result = client.client.update_item()
+        .table_name(UPLOADS_TABLE)
+        .key("content_id", AttributeValue::S(content_id.to_string()))
+        .update_expression("SET post_id = :post_id REMOVE expires")
+        .condition_expression("username = :username AND attribute_not_exists(post_id) AND expires > :now")
+        .expression_attribute_values(":post_id", AttributeValue::S(post_id.to_string()))
+        .expression_attribute_values(":username", AttributeValue::S(username.to_string()))
+        .expression_attribute_values(":now", AttributeValue::N(unix_secs(SystemTime::now()).to_string()))
+        .send()
+        .await;
+    let Err(e) = result else {
+        return Ok(());
+    };
+    if !matches!(e.as_service_error(), Some(UpdateItemError::ConditionalCheckFailedException(_))) {
+        return Err(e.into());
+    }
+    let reservation = client.get_item(UPLOADS_TABLE, [("content_id".into(), AttributeValue::S(content_id.to_string()))].into()).await?;
+    let owned = reservation.as_ref()
+        .and_then(|it| it.get("username")?.as_s().ok())
+        .is_some_and(|owner| owner == username);
+    let attached = reservation.as_ref().is_some_and(|it| it.contains_key("post_id"));
+    if owned && attached {
+        Err(ApiError::new(409, "media_already_attached", format!("media {content_id} is already part of a post")))
+    } else {
+        Err(ApiError::forbidden("media_not_owned", format!("media {content_id} was not uploaded by the caller or its reservation expired")))
+    }
+}
+
+/// Undoes [`claim`] when creating the post failed afterwards.
+pub async fn release(client: &DynamoDBClient, content_id: &str, post_id: &str) -> Result<(), ApiError> {
+    let result = client.client.update_item()
+        .table_name(UPLOADS_TABLE)
+        .key("content_id", AttributeValue::S(content_id.to_string()))
+        .update_expression("REMOVE post_id SET expires = :expires")
+        .condition_expression("post_id = :post_id")
+        .expression_attribute_values(":post_id", AttributeValue::S(post_id.to_string()))
+        .expression_attribute_values(":expires", AttributeValue::N(unix_secs(SystemTime::now() + RESERVATION_TTL).to_string()))
+        .send()
+        .await;
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) if matches!(e.as_service_error(), Some(UpdateItemError::ConditionalCheckFailedException(_))) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}